@@ -0,0 +1,182 @@
+//! Compares how the paths of directories are stored during a walk: a `CPathBuf` joined for every
+//! directory, as the walker did before, with the nodes of a `PathArena` whose paths are rebuilt
+//! in a reusable `PathBuffer` and which are freed with their subtree.
+//!
+//! The directories of `$BENCH_ROOT`, or of a generated tree, are loaded in memory first and then
+//! replayed so that only the path storage is measured: breadth-first the way the walker takes
+//! them from its shared queue, and depth-first the way it does from the deques of the workers
+//! once the queue is over its memory limit. Every allocation goes through a counting allocator.
+//!
+//! ```bash
+//! $ cargo bench --bench paths
+//! $ BENCH_ROOT=/usr cargo bench --bench paths
+//! ```
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::VecDeque,
+    env,
+    ffi::CString,
+    fs, hint,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use recursive_dir_walk::{
+    arena::{PathArena, PathBuffer},
+    cpathbuf::CPathBuf,
+};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::TempTree;
+
+const RUNS: usize = 7;
+/// Shape of the generated tree: subdirectories per level
+const FANOUT: [usize; 5] = [10, 10, 10, 10, 5];
+
+/// Counts allocations and the bytes allocated at the same time
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// A directory loaded in memory
+struct Dir {
+    name: CString,
+    subdirs: Vec<usize>,
+}
+
+/// Load the directories below `root`, which is the first one
+fn load(root: &Path) -> Vec<Dir> {
+    fn load_dir(path: &Path, name: CString, dirs: &mut Vec<Dir>) -> usize {
+        let index = dirs.len();
+        dirs.push(Dir {
+            name,
+            subdirs: Vec::new(),
+        });
+        // unreadable directories are walked as empty ones
+        let entries = fs::read_dir(path).into_iter().flatten().flatten();
+        for entry in entries {
+            if entry.file_type().is_ok_and(|ty| ty.is_dir()) {
+                let name = CString::new(entry.file_name().as_bytes()).unwrap();
+                let subdir = load_dir(&entry.path(), name, dirs);
+                dirs[index].subdirs.push(subdir);
+            }
+        }
+        index
+    }
+    let mut dirs = Vec::new();
+    load_dir(root, CString::default(), &mut dirs);
+    dirs
+}
+
+/// Take the oldest directory queued when breadth-first, else the newest
+fn next<T>(queue: &mut VecDeque<T>, breadth_first: bool) -> Option<T> {
+    match breadth_first {
+        true => queue.pop_front(),
+        false => queue.pop_back(),
+    }
+}
+
+/// Every directory owns its full path, joined to the path of its parent
+fn join<const BREADTH_FIRST: bool>(root: &Path, dirs: &[Dir]) -> usize {
+    let mut total = 0;
+    let mut queue = VecDeque::from([(CPathBuf::try_from(root).unwrap(), 0)]);
+    while let Some((path, index)) = next(&mut queue, BREADTH_FIRST) {
+        total += hint::black_box(path.as_slice()).len();
+        for &subdir in &dirs[index].subdirs {
+            queue.push_back((path.join(&dirs[subdir].name), subdir));
+        }
+    }
+    total
+}
+
+/// Every directory is a node of the arena, its path is rebuilt when read and its node freed
+/// once its subtree is done
+fn arena<const BREADTH_FIRST: bool>(root: &Path, dirs: &[Dir]) -> usize {
+    let arena = PathArena::new();
+    let mut nodes = arena.local();
+    let mut paths = PathBuffer::new();
+    let mut total = 0;
+    let mut queue = VecDeque::from([(arena.root(&CPathBuf::try_from(root).unwrap()), 0)]);
+    while let Some((node, index)) = next(&mut queue, BREADTH_FIRST) {
+        total += hint::black_box(paths.resolve(node).as_slice()).len();
+        for &subdir in &dirs[index].subdirs {
+            queue.push_back((nodes.push(node, &dirs[subdir].name), subdir));
+        }
+        // the subdirectories hold the node until they are done
+        unsafe { arena.release(node) };
+    }
+    total
+}
+
+fn bench(name: &str, root: &Path, dirs: &[Dir], replay: fn(&Path, &[Dir]) -> usize) -> usize {
+    let mut bytes = 0;
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            bytes = replay(root, dirs);
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    // a last run for the allocations alone
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(allocated, Ordering::Relaxed);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    replay(root, dirs);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let peak = PEAK.load(Ordering::Relaxed) - allocated;
+    println!(
+        "{name:<20} {:>9} dirs  min {:>10.3?}  median {:>10.3?}  {allocations:>9} allocations  \
+         peak {:>8} KiB",
+        dirs.len(),
+        times[0],
+        times[RUNS / 2],
+        peak >> 10,
+    );
+    bytes
+}
+
+fn main() {
+    let (root, tree) = match env::var_os("BENCH_ROOT") {
+        Some(root) => (PathBuf::from(root), None),
+        None => {
+            let tree = TempTree::build("paths", &FANOUT, 0).unwrap();
+            (tree.path().to_owned(), Some(tree))
+        }
+    };
+    let dirs = load(&root);
+    drop(tree);
+    let paths = [
+        bench("join, breadth-first", &root, &dirs, join::<true>),
+        bench("arena, breadth-first", &root, &dirs, arena::<true>),
+        bench("join, depth-first", &root, &dirs, join::<false>),
+        bench("arena, depth-first", &root, &dirs, arena::<false>),
+    ];
+    assert!(
+        paths.iter().all(|&bytes| bytes == paths[0]),
+        "all must build the same paths"
+    );
+}
@@ -0,0 +1,286 @@
+//! Compares the work-stealing scheduler of `Walker` with the model it replaced, where one
+//! coordinator thread parsed every `getdents64` buffer and handed syscalls to the workers over
+//! flume channels.
+//!
+//! Walks `$BENCH_ROOT` if set, otherwise a generated tree in the temporary directory. Both
+//! models keep the directories they hold open within `RLIMIT_NOFILE`, and count the directories
+//! they fail to open or read rather than stop.
+//!
+//! ```bash
+//! $ cargo bench --bench scheduler
+//! $ BENCH_ROOT=/usr cargo bench --bench scheduler
+//! ```
+
+use std::{
+    collections::VecDeque,
+    env,
+    ffi::CString,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use recursive_dir_walk::{
+    buffer::Buffer, cpathbuf::CPathBuf, dir_entry::DirEntryIter, read_buf::ReadBuf,
+    shared_fd::SharedFd, Walker,
+};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::TempTree;
+
+const THREADS: usize = 30;
+const BUFFER_SIZE: usize = 1024;
+const RUNS: usize = 7;
+/// Shape of the generated tree: subdirectories per level, and files per directory
+const FANOUT: [usize; 3] = [20, 20, 10];
+const FILES: usize = 10;
+/// File descriptors left to the rest of the process, as the walker does
+const RESERVED_FDS: usize = 64;
+
+fn work_stealing(root: &Path) -> u64 {
+    let count = AtomicU64::new(0);
+    Walker::new(root)
+        .threads(THREADS)
+        .buffer_size(BUFFER_SIZE)
+        .for_each(|_| {
+            count.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+    count.into_inner()
+}
+
+/// The coordinator model, reduced to what a walk needs, with the fd budget of the walker
+mod coordinator {
+    use super::*;
+
+    /// Directories the last walk failed to open or read
+    pub static FAILED: AtomicU64 = AtomicU64::new(0);
+
+    enum WorkRequest {
+        Open(CPathBuf, Option<SharedFd>),
+        ReadDir(CPathBuf, SharedFd),
+        Close(OwnedFd),
+    }
+    enum WorkResponse {
+        Open(CPathBuf, Option<SharedFd>, io::Result<SharedFd>),
+        ReadDir(CPathBuf, SharedFd, io::Result<Buffer>),
+        Close,
+    }
+
+    fn check(ret: libc::c_long) -> io::Result<libc::c_long> {
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret)
+    }
+
+    fn open(parent: Option<&SharedFd>, path: &CPathBuf) -> io::Result<OwnedFd> {
+        let flags = libc::O_CLOEXEC | libc::O_NOFOLLOW | libc::O_RDONLY | libc::O_DIRECTORY;
+        let ret = unsafe {
+            match parent {
+                Some(parent) => {
+                    let name = path.as_slice().rsplit(|&b| b == b'/').next().unwrap();
+                    let name = CString::new(name).unwrap();
+                    libc::openat64(parent.as_raw_fd(), name.as_ptr(), flags)
+                }
+                None => libc::openat64(libc::AT_FDCWD, path.as_ptr(), flags),
+            }
+        };
+        check(ret.into()).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as _) })
+    }
+
+    fn worker(req_recv: flume::Receiver<WorkRequest>, res_send: flume::Sender<WorkResponse>) {
+        while let Ok(req) = req_recv.recv() {
+            let res = match req {
+                WorkRequest::Open(path, parent) => {
+                    let res = open(parent.as_ref(), &path).map(SharedFd::new);
+                    WorkResponse::Open(path, parent, res)
+                }
+                WorkRequest::ReadDir(path, fd) => {
+                    let mut buf = Buffer::alloc(BUFFER_SIZE);
+                    let res = unsafe {
+                        check(libc::syscall(
+                            libc::SYS_getdents64,
+                            fd.as_raw_fd(),
+                            buf.data_mut().as_mut_ptr(),
+                            buf.len(),
+                        ))
+                        .map(|len| {
+                            buf.set_init_len(len as usize);
+                            buf
+                        })
+                    };
+                    WorkResponse::ReadDir(path, fd, res)
+                }
+                WorkRequest::Close(fd) => {
+                    drop(fd);
+                    WorkResponse::Close
+                }
+            };
+            if res_send.send(res).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Directories open at the same time, the soft `RLIMIT_NOFILE` less a reserve
+    fn fd_budget() -> usize {
+        let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0
+            || limit.rlim_cur == libc::RLIM_INFINITY
+        {
+            return usize::MAX;
+        }
+        (limit.rlim_cur as usize)
+            .saturating_sub(RESERVED_FDS)
+            .max(1)
+    }
+
+    pub fn walk(root: &Path) -> u64 {
+        let budget = fd_budget();
+        let (req_send, req_recv) = flume::unbounded();
+        let (res_send, res_recv) = flume::unbounded();
+        let workers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let req_recv = req_recv.clone();
+                let res_send = res_send.clone();
+                thread::spawn(move || worker(req_recv, res_send))
+            })
+            .collect();
+
+        // the directories open, or about to be, counted when their open is sent and until
+        // their close is done
+        let mut open_fds = 0;
+        // opens by full path waiting for the budget
+        let mut waiting = VecDeque::new();
+        let mut failed = 0;
+        let mut count = 0;
+        let mut in_progress = 0;
+        let send = |req: WorkRequest, in_progress: &mut usize| {
+            *in_progress += 1;
+            req_send.send(req).unwrap();
+        };
+        let release = |fd: SharedFd, in_progress: &mut usize| {
+            if let Some(fd) = fd.release() {
+                send(WorkRequest::Close(fd), in_progress);
+            }
+        };
+        waiting.push_back(CPathBuf::try_from(root).unwrap());
+        loop {
+            while open_fds < budget {
+                let Some(path) = waiting.pop_front() else {
+                    break;
+                };
+                open_fds += 1;
+                send(WorkRequest::Open(path, None), &mut in_progress);
+            }
+            if in_progress == 0 {
+                break;
+            }
+            let res = res_recv.recv().unwrap();
+            in_progress -= 1;
+            match res {
+                WorkResponse::Open(path, parent, fd) => {
+                    if let Some(parent) = parent {
+                        release(parent, &mut in_progress);
+                    }
+                    match fd {
+                        Ok(fd) => send(WorkRequest::ReadDir(path, fd), &mut in_progress),
+                        Err(_) => {
+                            open_fds -= 1;
+                            failed += 1;
+                        }
+                    }
+                }
+                WorkResponse::ReadDir(path, fd, buf) => {
+                    let buf = match buf {
+                        Ok(buf) => buf,
+                        Err(_) => {
+                            failed += 1;
+                            Buffer::alloc(0)
+                        }
+                    };
+                    if !buf.init().is_empty() {
+                        send(
+                            WorkRequest::ReadDir(path.clone(), fd.clone()),
+                            &mut in_progress,
+                        );
+                        for entry in DirEntryIter::new(&buf) {
+                            let Ok(entry) = entry else {
+                                failed += 1;
+                                break;
+                            };
+                            if entry.ty.is_dir() {
+                                let path = path.join(entry.c_name());
+                                // keep the parent open for the subdirectory while the budget
+                                // leaves room for the directories the workers are opening
+                                if open_fds + THREADS < budget {
+                                    open_fds += 1;
+                                    let req = WorkRequest::Open(path, Some(fd.clone()));
+                                    send(req, &mut in_progress);
+                                } else {
+                                    waiting.push_back(path);
+                                }
+                            }
+                            count += 1;
+                        }
+                    }
+                    release(fd, &mut in_progress);
+                }
+                WorkResponse::Close => open_fds -= 1,
+            }
+        }
+        drop(req_send);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        FAILED.store(failed, Ordering::Relaxed);
+        count
+    }
+}
+
+fn bench(name: &str, root: &Path, walk: fn(&Path) -> u64) -> u64 {
+    let mut entries = 0;
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            entries = walk(root);
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    println!(
+        "{name:<16} {entries:>9} entries  min {:>10.3?}  median {:>10.3?}",
+        times[0],
+        times[RUNS / 2]
+    );
+    entries
+}
+
+fn main() {
+    let (root, tree) = match env::var_os("BENCH_ROOT") {
+        Some(root) => (PathBuf::from(root), None),
+        None => {
+            let tree = TempTree::build("bench", &FANOUT, FILES).unwrap();
+            (tree.path().to_owned(), Some(tree))
+        }
+    };
+    // warm up the dentry cache, both models then read from memory
+    work_stealing(&root);
+    let stealing = bench("work stealing", &root, work_stealing);
+    let coordinator = bench("coordinator", &root, coordinator::walk);
+    let failed = coordinator::FAILED.load(Ordering::Relaxed);
+    if failed > 0 {
+        println!("coordinator: {failed} directories could not be opened or read");
+    }
+    drop(tree);
+    assert_eq!(
+        stealing, coordinator,
+        "both models must find the same entries"
+    );
+}
@@ -0,0 +1,16 @@
+#![no_main]
+
+use std::os::unix::ffi::OsStrExt;
+
+use libfuzzer_sys::fuzz_target;
+use recursive_dir_walk::{buffer::Buffer, dir_entry::DirEntryIter};
+
+fuzz_target!(|data: &[u8]| {
+    let buf = Buffer::from_slice(data);
+    for entry in DirEntryIter::new(&buf) {
+        match entry {
+            Ok(entry) => assert_eq!(entry.c_name().to_bytes(), entry.name.as_bytes()),
+            Err(_) => break,
+        }
+    }
+});
@@ -0,0 +1,515 @@
+use std::{
+    alloc::{self, Layout},
+    collections::HashMap,
+    ffi::CStr,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ptr::{self, NonNull},
+    slice,
+    sync::{
+        atomic::{self, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::cpathbuf::{CPath, CPathBuf};
+
+/// Size of the chunks nodes are allocated from; a name too large for one gets a chunk of its own
+const CHUNK_SIZE: usize = 4 << 10;
+
+/// Storage for the directories of a walk, each stored once as its name and a pointer to its
+/// parent rather than as its full path
+///
+/// Every worker appends nodes to chunks of its own through a [`LocalArena`], and rebuilds full
+/// paths in a [`PathBuffer`] when it needs them. A node is created with a reference for its
+/// owner and holds one on its parent; once [`PathArena::release`] dropped the last one, the
+/// parent is released too, and a chunk is freed once none of its nodes is left. The chunks
+/// still allocated are freed with the arena.
+pub struct PathArena {
+    /// the chunks allocated, by address
+    chunks: Mutex<HashMap<usize, Chunk>>,
+    bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    generations: AtomicU64,
+}
+
+impl PathArena {
+    pub fn new() -> Self {
+        Self {
+            chunks: Mutex::new(HashMap::new()),
+            bytes: AtomicUsize::new(0),
+            peak_bytes: AtomicUsize::new(0),
+            generations: AtomicU64::new(0),
+        }
+    }
+    /// Memory in bytes allocated for nodes
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+    /// Most memory in bytes allocated for nodes at the same time
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes.load(Ordering::Relaxed)
+    }
+    /// Number of chunks allocated
+    pub fn chunks(&self) -> usize {
+        self.chunks.lock().unwrap().len()
+    }
+    /// Store the root of a walk, whose name is its whole path
+    pub fn root(&self, path: &CPath) -> Node<'_> {
+        let name = path.as_c_str();
+        let (chunk, ptr) = self.alloc(record_size(name));
+        // the chunk holds the root alone
+        unsafe { chunk.as_ref() }.live.store(1, Ordering::Relaxed);
+        unsafe { Node::write(ptr, chunk, None, path.as_slice().len(), 0, name) }
+    }
+    /// Appends nodes for a single worker
+    pub fn local(&self) -> LocalArena<'_> {
+        LocalArena {
+            arena: self,
+            chunk: None,
+            next: NonNull::dangling(),
+            remaining: 0,
+        }
+    }
+    /// Drop a reference to `node`, and free it once it was the last one
+    ///
+    /// # Safety
+    ///
+    /// The caller holds a reference to `node`, which it does not use afterwards.
+    pub unsafe fn release(&self, node: Node<'_>) {
+        let mut next = Some(node.ptr);
+        while let Some(ptr) = next {
+            let header = ptr.as_ref();
+            if header.refs.fetch_sub(1, Ordering::Release) != 1 {
+                return;
+            }
+            atomic::fence(Ordering::Acquire);
+            next = header.parent;
+            self.release_chunk(header.chunk);
+        }
+    }
+    /// Returns the chunk, with no reference to it, and the space for nodes in it
+    fn alloc(&self, size: usize) -> (NonNull<ChunkHeader>, NonNull<u8>) {
+        let layout =
+            Layout::from_size_align(NODES_OFFSET + size, mem::align_of::<Header>()).unwrap();
+        let ptr = NonNull::new(unsafe { alloc::alloc(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        let chunk = ptr.cast::<ChunkHeader>();
+        unsafe {
+            chunk.as_ptr().write(ChunkHeader {
+                live: AtomicUsize::new(0),
+                generation: self.generations.fetch_add(1, Ordering::Relaxed),
+            })
+        };
+        let addr = ptr.as_ptr() as usize;
+        self.chunks
+            .lock()
+            .unwrap()
+            .insert(addr, Chunk { ptr, layout });
+        let bytes = self.bytes.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak_bytes.fetch_max(bytes, Ordering::Relaxed);
+        // the nodes start after the header of the chunk, within the allocation
+        (chunk, unsafe { ptr.add(NODES_OFFSET) })
+    }
+    /// Drop a reference to a chunk, and free it once it was the last one
+    ///
+    /// Safety: the caller holds a reference to the chunk
+    unsafe fn release_chunk(&self, chunk: NonNull<ChunkHeader>) {
+        if chunk.as_ref().live.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        atomic::fence(Ordering::Acquire);
+        let chunk = self
+            .chunks
+            .lock()
+            .unwrap()
+            .remove(&(chunk.as_ptr() as usize));
+        if let Some(chunk) = chunk {
+            self.bytes.fetch_sub(chunk.layout.size(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for PathArena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for PathArena {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathArena")
+            .field("chunks", &self.chunks())
+            .field("bytes", &self.bytes())
+            .finish()
+    }
+}
+
+struct Chunk {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// the chunk is freed once no node in it is used anymore
+unsafe impl Send for Chunk {}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Start of every chunk, followed by its nodes
+#[repr(C)]
+struct ChunkHeader {
+    /// nodes of the chunk still used, and the [`LocalArena`] still appending to it
+    live: AtomicUsize,
+    /// distinguishes the nodes of the chunk from those of a chunk allocated at the same address
+    /// before
+    generation: u64,
+}
+
+/// Offset of the first node of a chunk
+const NODES_OFFSET: usize =
+    mem::size_of::<ChunkHeader>().next_multiple_of(mem::align_of::<Header>());
+
+/// The rest of the chunk a worker appends its nodes to
+///
+/// The chunk is kept as long as nodes are appended to it, the last one until the arena is
+/// dropped.
+pub struct LocalArena<'a> {
+    arena: &'a PathArena,
+    chunk: Option<NonNull<ChunkHeader>>,
+    next: NonNull<u8>,
+    remaining: usize,
+}
+
+// the rest of the chunk is only written through the `LocalArena`
+unsafe impl Send for LocalArena<'_> {}
+
+impl<'a> LocalArena<'a> {
+    /// Store a subdirectory of `parent`, which is kept until the subdirectory is released
+    ///
+    /// The caller owns the reference the node is created with. `parent` must not have been
+    /// freed.
+    pub fn push(&mut self, parent: Node<'a>, name: &CStr) -> Node<'a> {
+        let size = record_size(name);
+        if size > self.remaining {
+            let chunk_size = size.max(CHUNK_SIZE - NODES_OFFSET);
+            let (chunk, ptr) = self.arena.alloc(chunk_size);
+            unsafe { chunk.as_ref() }.live.store(1, Ordering::Relaxed);
+            if let Some(full) = self.chunk.replace(chunk) {
+                // Safety: the reference of the `LocalArena` to its previous chunk
+                unsafe { self.arena.release_chunk(full) };
+            }
+            self.next = ptr;
+            self.remaining = chunk_size;
+        }
+        let chunk = self.chunk.unwrap();
+        unsafe { chunk.as_ref() }
+            .live
+            .fetch_add(1, Ordering::Relaxed);
+        parent.header().refs.fetch_add(1, Ordering::Relaxed);
+        let ptr = self.next;
+        // the record ends within the chunk
+        self.next = unsafe { ptr.add(size) };
+        self.remaining -= size;
+        let separator = parent.needs_separator() as usize;
+        let path_len = parent.path_len() + separator + name.to_bytes().len();
+        unsafe { Node::write(ptr, chunk, Some(parent), path_len, parent.depth() + 1, name) }
+    }
+    /// Forget which arena the nodes are appended to
+    ///
+    /// Safety: the `LocalArena` must not be used once the arena is dropped
+    pub(crate) unsafe fn into_static(self) -> LocalArena<'static> {
+        LocalArena {
+            arena: &*(self.arena as *const PathArena),
+            chunk: self.chunk,
+            next: self.next,
+            remaining: self.remaining,
+        }
+    }
+}
+
+impl fmt::Debug for LocalArena<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalArena")
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+/// Fixed part of a node, followed by its zero-terminated name
+#[repr(C)]
+struct Header {
+    parent: Option<NonNull<Header>>,
+    chunk: NonNull<ChunkHeader>,
+    /// length of the full path, without its zero character
+    path_len: usize,
+    /// the owner of the node and its children
+    refs: AtomicU32,
+    depth: u32,
+    /// length of the name, with its zero character
+    name_len: u32,
+}
+
+/// Bytes taken by the node of `name`, keeping the next one aligned
+fn record_size(name: &CStr) -> usize {
+    (mem::size_of::<Header>() + name.to_bytes_with_nul().len())
+        .next_multiple_of(mem::align_of::<Header>())
+}
+
+/// A directory stored in a [`PathArena`]
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    ptr: NonNull<Header>,
+    arena: PhantomData<&'a PathArena>,
+}
+
+// nodes are never written once created, but for their reference count
+unsafe impl Send for Node<'_> {}
+unsafe impl Sync for Node<'_> {}
+
+impl<'a> Node<'a> {
+    /// Safety: `ptr` is aligned for `Header` and valid for writes of `record_size(name)` bytes
+    /// within `chunk`
+    unsafe fn write(
+        ptr: NonNull<u8>,
+        chunk: NonNull<ChunkHeader>,
+        parent: Option<Node<'a>>,
+        path_len: usize,
+        depth: usize,
+        name: &CStr,
+    ) -> Self {
+        let header = ptr.cast::<Header>();
+        let name = name.to_bytes_with_nul();
+        header.as_ptr().write(Header {
+            parent: parent.map(|parent| parent.ptr),
+            chunk,
+            path_len,
+            refs: AtomicU32::new(1),
+            depth: depth.try_into().unwrap(),
+            name_len: name.len().try_into().unwrap(),
+        });
+        ptr::copy_nonoverlapping(name.as_ptr(), header.as_ptr().add(1).cast(), name.len());
+        Self {
+            ptr: header,
+            arena: PhantomData,
+        }
+    }
+    fn header(&self) -> &'a Header {
+        unsafe { self.ptr.as_ref() }
+    }
+    pub fn parent(&self) -> Option<Node<'a>> {
+        self.header().parent.map(|ptr| Node {
+            ptr,
+            arena: PhantomData,
+        })
+    }
+    /// The name of the directory, or the whole path for the root
+    pub fn name(&self) -> &'a CStr {
+        let len = self.header().name_len as usize;
+        // Safety: the name was copied right after the header, with its zero character
+        unsafe {
+            let name = slice::from_raw_parts(self.ptr.as_ptr().add(1).cast::<u8>(), len);
+            CStr::from_bytes_with_nul_unchecked(name)
+        }
+    }
+    /// Number of ancestors, 0 for the root
+    pub fn depth(&self) -> usize {
+        self.header().depth as usize
+    }
+    /// Length of the full path, without its zero character
+    pub fn path_len(&self) -> usize {
+        self.header().path_len
+    }
+    /// Bytes taken by the node in the arena
+    pub fn size(&self) -> usize {
+        record_size(self.name())
+    }
+    /// The generation of its chunk, which tells the node apart from a freed one at the same
+    /// address
+    fn generation(&self) -> u64 {
+        unsafe { self.header().chunk.as_ref() }.generation
+    }
+    /// Build the full path in a new buffer, see [`PathBuffer`] to build many
+    pub fn to_path_buf(&self) -> CPathBuf {
+        PathBuffer::new().resolve(*self).to_owned()
+    }
+    /// Whether the name of a subdirectory has to be separated from this path by a `/`
+    fn needs_separator(&self) -> bool {
+        !matches!(self.name().to_bytes().last(), None | Some(b'/'))
+    }
+    /// Forget which arena the node belongs to
+    ///
+    /// Safety: the node must not be used once the arena is dropped
+    pub(crate) unsafe fn into_static(self) -> Node<'static> {
+        Node {
+            ptr: self.ptr,
+            arena: PhantomData,
+        }
+    }
+}
+
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl Eq for Node<'_> {}
+
+impl fmt::Debug for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Node").field(&self.to_path_buf()).finish()
+    }
+}
+
+/// Rebuilds the full paths of nodes, keeping the components shared with the previous path
+///
+/// A worker reads directories close to the one it read before, usually a sibling or a
+/// subdirectory, so only the last components are copied.
+#[derive(Debug, Default)]
+pub struct PathBuffer<'a> {
+    /// the path last built, followed by its zero character
+    buf: Vec<u8>,
+    /// the nodes of the components of `buf` and their generations, by depth; they may have been
+    /// freed since, and are only read once found among the ancestors of a node
+    nodes: Vec<(Node<'a>, u64)>,
+}
+
+impl<'a> PathBuffer<'a> {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+    /// Build the full path of `node`, which must not have been freed
+    pub fn resolve(&mut self, node: Node<'a>) -> &CPath {
+        // the deepest ancestor in the buffer already
+        let mut kept = 0;
+        let mut ancestor = Some(node);
+        while let Some(node) = ancestor {
+            if self.nodes.get(node.depth()) == Some(&(node, node.generation())) {
+                kept = node.depth() + 1;
+                break;
+            }
+            ancestor = node.parent();
+        }
+        self.nodes.truncate(kept);
+        self.buf
+            .truncate(self.nodes.last().map_or(0, |(node, _)| node.path_len()));
+        self.nodes.resize(node.depth() + 1, (node, 0));
+        let mut next = Some(node);
+        for depth in (kept..=node.depth()).rev() {
+            let node = next.unwrap();
+            self.nodes[depth] = (node, node.generation());
+            next = node.parent();
+        }
+        for depth in kept..=node.depth() {
+            if depth > 0 && self.nodes[depth - 1].0.needs_separator() {
+                self.buf.push(b'/');
+            }
+            self.buf.extend(self.nodes[depth].0.name().to_bytes());
+        }
+        debug_assert_eq!(self.buf.len(), node.path_len());
+        self.buf.push(0);
+        // Safety: the names have a single zero character, which was stripped
+        CPath::new(unsafe { CStr::from_bytes_with_nul_unchecked(&self.buf) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn resolves_like_join() {
+        let arena = PathArena::new();
+        let mut local = arena.local();
+        let mut paths = PathBuffer::new();
+        for root in ["/", "/usr", "usr/", "", "."] {
+            let root_path = CPathBuf::try_from(root).unwrap();
+            let root = arena.root(&root_path);
+            let a = local.push(root, c"a");
+            let b = local.push(a, c"b");
+            let c = local.push(a, c"c");
+            let d = local.push(c, c"d");
+            let nodes = [
+                (root, root_path.clone()),
+                (a, root_path.join(c"a")),
+                (b, root_path.join(c"a").join(c"b")),
+                (c, root_path.join(c"a").join(c"c")),
+                (d, root_path.join(c"a").join(c"c").join(c"d")),
+            ];
+            // deeper, a sibling, shallower, and back to a shared ancestor
+            for i in [0, 4, 2, 3, 1, 4, 0, 2] {
+                let (node, expected) = &nodes[i];
+                assert_eq!(paths.resolve(*node), &**expected);
+                assert_eq!(node.path_len(), expected.as_slice().len());
+                assert_eq!(&node.to_path_buf(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_hold_many_nodes() {
+        let arena = PathArena::new();
+        let mut local = arena.local();
+        let root = arena.root(&CPathBuf::from(c"/"));
+        let mut parent = root;
+        for _ in 0..10_000 {
+            parent = local.push(parent, c"name");
+        }
+        assert_eq!(parent.depth(), 10_000);
+        assert_eq!(parent.path_len(), 10_000 * 5);
+        // dozens of nodes a chunk
+        assert!(arena.chunks() < 10_000 / 64);
+        let long = CString::new(vec![b'x'; CHUNK_SIZE]).unwrap();
+        let node = local.push(root, &long);
+        assert_eq!(node.name(), &*long);
+    }
+
+    #[test]
+    fn frees_finished_subtrees() {
+        let arena = PathArena::new();
+        let mut local = arena.local();
+        let mut paths = PathBuffer::new();
+        let root = arena.root(&CPathBuf::from(c"/root"));
+        let name = CString::new(vec![b'x'; 1000]).unwrap();
+        // enough nodes for a few chunks, all in the subtree of `a`
+        let a = local.push(root, c"a");
+        let b = local.push(a, c"b");
+        let leaves: Vec<_> = (0..200).map(|_| local.push(b, &name)).collect();
+        let c = local.push(root, c"c");
+        assert!(arena.chunks() > 3);
+        let peak = arena.bytes();
+        assert_eq!(arena.peak_bytes(), peak);
+
+        assert_eq!(paths.resolve(leaves[0]).as_slice().len(), 1010);
+        unsafe {
+            arena.release(a);
+            arena.release(b);
+            for leaf in leaves {
+                arena.release(leaf);
+            }
+        }
+        // the chunk of the root and the one appended to, which holds `c`, are left
+        assert_eq!(arena.chunks(), 2);
+        assert!(arena.bytes() < peak);
+        assert_eq!(arena.peak_bytes(), peak);
+        // nodes at the addresses of freed ones are not mistaken for them
+        let d = local.push(c, c"d");
+        assert_eq!(paths.resolve(d).to_string(), "/root/c/d");
+
+        unsafe {
+            arena.release(d);
+            arena.release(c);
+            arena.release(root);
+        }
+        assert_eq!(arena.chunks(), 1);
+    }
+}
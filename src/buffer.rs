@@ -1,29 +1,75 @@
-use crate::read_buf::{ReadBuf, ReadBuffer, SubReadBuffer};
+use crate::{
+    dir_entry::linux_dirent64,
+    read_buf::{ReadBuf, ReadBuffer, SubReadBuffer},
+};
+use crossbeam_queue::ArrayQueue;
 use std::{
-    alloc::{alloc, dealloc, Layout},
-    mem, slice,
+    alloc::{alloc, dealloc, handle_alloc_error, Layout, LayoutError},
+    io, mem, ptr, slice,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
+/// Heap buffer aligned for the `linux_dirent64` records returned by `getdents64`
 #[derive(Debug)]
 pub struct Buffer {
     buf: ReadBuffer<'static>,
 }
 
 impl Buffer {
+    const ALIGN: usize = mem::align_of::<linux_dirent64>();
+    /// Largest capacity a buffer can be allocated with, `isize::MAX` rounded down to the alignment
+    pub const MAX_CAPACITY: usize = isize::MAX as usize & !(Self::ALIGN - 1);
+
+    /// Allocate a buffer of `capacity` bytes, aborting the process if memory is exhausted
+    ///
+    /// Panics above [`Buffer::MAX_CAPACITY`].
     pub fn alloc(capacity: usize) -> Self {
-        let layout = Layout::from_size_align(capacity, mem::align_of::<u8>()).unwrap();
-        let buf = unsafe {
-            let ptr = alloc(layout);
-            slice::from_raw_parts_mut(ptr.cast::<mem::MaybeUninit<u8>>(), layout.size())
-        };
-        Self {
+        let layout = Self::layout(capacity).expect("buffer capacity must not exceed isize::MAX");
+        Self::from_layout(layout).unwrap_or_else(|| handle_alloc_error(layout))
+    }
+    /// Allocate a buffer of `capacity` bytes, failing with [`io::ErrorKind::OutOfMemory`] if
+    /// memory is exhausted or `capacity` exceeds [`Buffer::MAX_CAPACITY`]
+    pub fn try_alloc(capacity: usize) -> io::Result<Self> {
+        Self::layout(capacity)
+            .ok()
+            .and_then(Self::from_layout)
+            .ok_or_else(|| io::ErrorKind::OutOfMemory.into())
+    }
+    fn layout(capacity: usize) -> Result<Layout, LayoutError> {
+        // allocating zero bytes is undefined behaviour
+        assert!(capacity > 0, "buffer capacity must not be zero");
+        Layout::from_size_align(capacity, Self::ALIGN)
+    }
+    fn from_layout(layout: Layout) -> Option<Self> {
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            return None;
+        }
+        let buf =
+            unsafe { slice::from_raw_parts_mut(ptr.cast::<mem::MaybeUninit<u8>>(), layout.size()) };
+        Some(Self {
             buf: ReadBuffer::new(buf),
+        })
+    }
+    /// Copy `data` into a new buffer, as if it was filled in by `getdents64`
+    pub fn from_slice(data: &[u8]) -> Self {
+        // never allocate zero bytes
+        let mut buf = Self::alloc(data.len().max(Self::ALIGN));
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                buf.data_mut().as_mut_ptr().cast::<u8>(),
+                data.len(),
+            );
+            buf.set_init_len(data.len());
         }
+        buf
     }
 }
 
 impl ReadBuf for Buffer {
-    type SubReadBuf<'s> = SubReadBuffer<'s, ReadBuffer<'static>>
+    type SubReadBuf<'s>
+        = SubReadBuffer<'s, ReadBuffer<'static>>
     where
         Self: 's;
 
@@ -61,10 +107,86 @@ impl ReadBuf for Buffer {
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        let layout =
-            Layout::from_size_align(self.buf.data_mut().len(), mem::align_of::<u8>()).unwrap();
+        let layout = Layout::from_size_align(self.buf.data_mut().len(), Self::ALIGN).unwrap();
         unsafe {
             dealloc(self.buf.data_mut().as_mut_ptr().cast::<u8>(), layout);
         }
     }
 }
+
+/// Lock-free pool of buffers, so that reading a directory does not allocate once the pool holds
+/// a buffer large enough
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: ArrayQueue<Buffer>,
+    allocations: AtomicU64,
+    reuses: AtomicU64,
+}
+
+impl BufferPool {
+    /// Create a pool keeping at most `capacity` buffers
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffers: ArrayQueue::new(capacity),
+            allocations: AtomicU64::new(0),
+            reuses: AtomicU64::new(0),
+        }
+    }
+    /// Take a buffer of at least `len` bytes from the pool, or allocate one
+    ///
+    /// Pooled buffers too small for `len` are put back for smaller requests, each is looked at
+    /// once at most.
+    pub fn take(&self, len: usize) -> io::Result<Buffer> {
+        for _ in 0..self.buffers.len() {
+            let Some(mut buf) = self.buffers.pop() else {
+                break;
+            };
+            if buf.len() >= len {
+                self.reuses.fetch_add(1, Ordering::Relaxed);
+                buf.clear();
+                return Ok(buf);
+            }
+            self.put(buf);
+        }
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Buffer::try_alloc(len)
+    }
+    /// Return a buffer to the pool, it is freed if the pool is full
+    pub fn put(&self, buf: Buffer) {
+        let _ = self.buffers.push(buf);
+    }
+    /// Buffers allocated because the pool had none large enough
+    pub fn allocations(&self) -> u64 {
+        self.allocations.load(Ordering::Relaxed)
+    }
+    /// Buffers taken from the pool without allocating
+    pub fn reuses(&self) -> u64 {
+        self.reuses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_buffers_too_small_for_a_take() {
+        let pool = BufferPool::new(4);
+        pool.put(Buffer::try_alloc(1024).unwrap());
+        pool.put(Buffer::try_alloc(4096).unwrap());
+        assert_eq!(pool.take(2048).unwrap().len(), 4096);
+        assert_eq!(pool.take(1024).unwrap().len(), 1024);
+        assert_eq!(pool.reuses(), 2);
+        assert_eq!(pool.allocations(), 0);
+        assert_eq!(pool.take(1024).unwrap().len(), 1024);
+        assert_eq!(pool.allocations(), 1);
+    }
+
+    #[test]
+    fn fails_to_allocate_beyond_the_layout_limit() {
+        for capacity in [Buffer::MAX_CAPACITY + 1, usize::MAX] {
+            let err = Buffer::try_alloc(capacity).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+        }
+    }
+}
@@ -1,98 +1,484 @@
 use std::{
-    ffi::{CStr, OsStr, OsString},
-    fmt,
+    borrow::Borrow,
+    ffi::{CStr, CString, NulError, OsStr, OsString},
+    fmt, mem,
     ops::Deref,
-    path::Path,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
 };
 
-#[derive(Clone)]
-pub struct CPathBuf(Box<[u8]>);
+/// A borrowed path followed by its zero character, so that it can be passed to syscalls as is
+///
+/// It is to [`CPathBuf`] what [`Path`] is to [`PathBuf`]. The parent of a path is not followed by
+/// a zero character, so [`CPath::parent`] allocates a new path, while [`CPath::strip_prefix`]
+/// borrows the rest of this one.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct CPath([u8]);
 
-impl CPathBuf {
-    pub fn join(&self, name: &CStr) -> Self {
+impl CPath {
+    pub fn new(path: &CStr) -> &Self {
+        unsafe { Self::from_bytes_with_nul_unchecked(path.to_bytes_with_nul()) }
+    }
+    /// Safety: `bytes` ends with its only zero character
+    unsafe fn from_bytes_with_nul_unchecked(bytes: &[u8]) -> &Self {
+        // `CPath` is a transparent wrapper of `[u8]`
+        &*(bytes as *const [u8] as *const Self)
+    }
+    /// The path without its zero character
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0[..self.0.len() - 1]
+    }
+    pub fn as_c_str(&self) -> &CStr {
+        unsafe { CStr::from_bytes_with_nul_unchecked(&self.0) }
+    }
+    pub fn as_os_str(&self) -> &OsStr {
+        OsStr::from_bytes(self.as_slice())
+    }
+    pub fn to_path(&self) -> &Path {
+        Path::new(self.as_os_str())
+    }
+    pub fn is_absolute(&self) -> bool {
+        self.as_slice().starts_with(b"/")
+    }
+    /// The components of the path: `/` first if it is absolute, then every name between
+    /// separators, skipping empty ones and `.` unless the path starts with it
+    pub fn components(&self) -> Components<'_> {
+        Components {
+            path: self.as_slice(),
+            pos: 0,
+        }
+    }
+    /// The last component, unless the path ends with the root, `.` or `..`
+    pub fn file_name(&self) -> Option<&OsStr> {
+        self.components()
+            .next_back()
+            .filter(|name| !matches!(name.as_bytes(), b"/" | b"." | b".."))
+    }
+    /// The path without its last component, `None` if it has none or ends with the root
+    pub fn parent(&self) -> Option<CPathBuf> {
+        self.parent_len()
+            .map(|len| CPathBuf::from_vec(self.as_slice()[..len].to_vec()))
+    }
+    /// Length of the path of the parent, without the separators before the last component
+    fn parent_len(&self) -> Option<usize> {
+        let path = self.as_slice();
+        let mut components = self.components();
+        let last = components.next_back()?;
+        if last == "/" {
+            return None;
+        }
+        let start = last.as_bytes().as_ptr() as usize - path.as_ptr() as usize;
+        let len = path[..start]
+            .iter()
+            .rposition(|&b| b != b'/')
+            .map_or(0, |i| i + 1);
+        // the root is the only path ending with a separator
+        Some(if len == 0 && start > 0 { 1 } else { len })
+    }
+    /// The rest of the path after the components of `base`, `None` if it does not start with
+    /// them
+    pub fn strip_prefix(&self, base: &CPath) -> Option<&CPath> {
+        let mut components = self.components();
+        for prefix in base.components() {
+            if components.next() != Some(prefix) {
+                return None;
+            }
+        }
+        let rest = &self.0[components.pos..];
+        let skip = rest.iter().take_while(|&&b| b == b'/').count();
+        Some(unsafe { Self::from_bytes_with_nul_unchecked(&rest[skip..]) })
+    }
+    /// Create an owned path with `name` appended, see [`CPathBuf::push`]
+    pub fn join(&self, name: &CStr) -> CPathBuf {
         let name = name.to_bytes_with_nul();
-        // `self.path.len()` includes the zero character which we'll replace with '/' and append `name` and it's zero character
         let mut buf = Vec::with_capacity(self.0.len() + name.len());
-        buf.extend(&self.0[..self.0.len() - 1]);
-        buf.push(b'/');
+        buf.extend(self.as_slice());
+        if self.needs_separator() {
+            buf.push(b'/');
+        }
         buf.extend(name);
-        Self(buf.into_boxed_slice())
+        CPathBuf(buf.into_boxed_slice())
     }
-    pub fn as_slice(&self) -> &[u8] {
-        &self.0[..self.0.len() - 1]
+    /// Whether a name appended to the path has to be separated from it by a `/`
+    pub(crate) fn needs_separator(&self) -> bool {
+        !matches!(self.as_slice().last(), None | Some(b'/'))
     }
 }
 
-impl AsRef<CStr> for CPathBuf {
+impl Deref for CPath {
+    type Target = CStr;
+    fn deref(&self) -> &CStr {
+        self.as_c_str()
+    }
+}
+
+impl AsRef<CStr> for CPath {
     fn as_ref(&self) -> &CStr {
-        &self
+        self
     }
 }
 
-impl Deref for CPathBuf {
-    type Target = CStr;
-    fn deref(&self) -> &CStr {
-        unsafe { CStr::from_bytes_with_nul_unchecked(&self.0) }
+impl AsRef<OsStr> for CPath {
+    fn as_ref(&self) -> &OsStr {
+        self.as_os_str()
     }
 }
 
-impl From<&str> for CPathBuf {
-    fn from(src: &str) -> Self {
-        let mut buf = Vec::with_capacity(src.len() + 1);
-        buf.extend(src.as_bytes());
-        buf.push(0);
-        Self(buf.into_boxed_slice())
+impl AsRef<Path> for CPath {
+    fn as_ref(&self) -> &Path {
+        self.to_path()
     }
 }
 
-impl From<&OsStr> for CPathBuf {
-    fn from(src: &OsStr) -> Self {
-        let buf = {
-            #[cfg(target_family = "unix")]
-            {
-                use std::os::unix::ffi::OsStrExt;
-                let src = src.as_bytes();
-                let mut buf = Vec::with_capacity(src.len() + 1);
-                buf.extend(src);
-                buf.push(0);
-                buf
+impl ToOwned for CPath {
+    type Owned = CPathBuf;
+    fn to_owned(&self) -> CPathBuf {
+        CPathBuf(self.0.into())
+    }
+}
+
+impl fmt::Debug for CPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_os_str(), f)
+    }
+}
+
+/// Displays the path lossily, like [`Path::display`]
+impl fmt::Display for CPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.to_path().display(), f)
+    }
+}
+
+/// Iterator over the components of a [`CPath`], see [`CPath::components`]
+#[derive(Debug, Clone)]
+pub struct Components<'a> {
+    path: &'a [u8],
+    /// where the components not yet yielded from the front start
+    pos: usize,
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a OsStr;
+    fn next(&mut self) -> Option<&'a OsStr> {
+        if self.pos == 0 && self.path.starts_with(b"/") {
+            self.pos = 1;
+            return Some(OsStr::new("/"));
+        }
+        loop {
+            let first = self.pos == 0;
+            self.pos += self.path[self.pos..]
+                .iter()
+                .take_while(|&&b| b == b'/')
+                .count();
+            let rest = &self.path[self.pos..];
+            if rest.is_empty() {
+                return None;
+            }
+            let name = &rest[..rest.iter().position(|&b| b == b'/').unwrap_or(rest.len())];
+            self.pos += name.len();
+            if name != b"." || first {
+                return Some(OsStr::from_bytes(name));
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for Components<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let mut rest = &self.path[self.pos..];
+        loop {
+            let len = rest.iter().rposition(|&b| b != b'/').map_or(0, |i| i + 1);
+            if len == 0 {
+                // only the root may be left
+                if self.pos == 0 && !rest.is_empty() {
+                    self.path = &self.path[..1];
+                    self.pos = 1;
+                    return Some(OsStr::new("/"));
+                }
+                self.path = &self.path[..self.pos];
+                return None;
             }
-            #[cfg(target_family = "windows")]
-            {
-                // origin: https://stackoverflow.com/a/59224987
-                let mut buf = Vec::new();
-                use std::os::windows::ffi::OsStrExt;
-                let it = src
-                    .encode_wide()
-                    .chain(Some(0))
-                    .map(|b| {
-                        let b = b.to_ne_bytes();
-                        b.get(0).map(|s| *s).into_iter().chain(b.get(1).map(|s| *s))
-                    })
-                    .flatten();
-                buf.extend(it);
-                buf.shrink_to_fit();
-                buf
+            let start = rest[..len]
+                .iter()
+                .rposition(|&b| b == b'/')
+                .map_or(0, |i| i + 1);
+            let name = &rest[start..len];
+            rest = &rest[..start];
+            let first = self.pos == 0 && start == 0;
+            if name != b"." || first {
+                self.path = &self.path[..self.pos + start];
+                return Some(OsStr::from_bytes(name));
             }
+        }
+    }
+}
+
+/// An owned path followed by its zero character, see [`CPath`]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CPathBuf(Box<[u8]>);
+
+impl CPathBuf {
+    /// Panics if `path` contains a zero character
+    fn from_vec(mut path: Vec<u8>) -> Self {
+        assert!(!path.contains(&0), "path contains a zero character");
+        path.push(0);
+        Self(path.into_boxed_slice())
+    }
+    pub fn as_c_path(&self) -> &CPath {
+        unsafe { CPath::from_bytes_with_nul_unchecked(&self.0) }
+    }
+    /// Append `name`, separated by a `/` unless the path is empty or already ends with one
+    ///
+    /// Unlike [`PathBuf::push`], an absolute `name` is appended too.
+    pub fn push(&mut self, name: &CStr) {
+        *self = self.join(name);
+    }
+    /// Truncate the path to its parent, returns `false` if it has none
+    pub fn pop(&mut self) -> bool {
+        let Some(len) = self.parent_len() else {
+            return false;
         };
-        Self(buf.into_boxed_slice())
+        let mut path = mem::take(&mut self.0).into_vec();
+        path.truncate(len);
+        path.push(0);
+        self.0 = path.into_boxed_slice();
+        true
+    }
+    pub fn into_c_string(self) -> CString {
+        unsafe { CString::from_vec_with_nul_unchecked(self.0.into_vec()) }
+    }
+    pub fn into_os_string(self) -> OsString {
+        let mut path = self.0.into_vec();
+        path.pop();
+        OsString::from_vec(path)
+    }
+    pub fn into_path_buf(self) -> PathBuf {
+        self.into_os_string().into()
+    }
+}
+
+impl Deref for CPathBuf {
+    type Target = CPath;
+    fn deref(&self) -> &CPath {
+        self.as_c_path()
+    }
+}
+
+impl Borrow<CPath> for CPathBuf {
+    fn borrow(&self) -> &CPath {
+        self
+    }
+}
+
+impl AsRef<CPath> for CPathBuf {
+    fn as_ref(&self) -> &CPath {
+        self
+    }
+}
+
+impl AsRef<CStr> for CPathBuf {
+    fn as_ref(&self) -> &CStr {
+        self
+    }
+}
+
+impl AsRef<OsStr> for CPathBuf {
+    fn as_ref(&self) -> &OsStr {
+        self.as_os_str()
+    }
+}
+
+impl AsRef<Path> for CPathBuf {
+    fn as_ref(&self) -> &Path {
+        self.to_path()
+    }
+}
+
+/// Fails if `src` contains a zero character
+impl TryFrom<&str> for CPathBuf {
+    type Error = NulError;
+    fn try_from(src: &str) -> Result<Self, NulError> {
+        Self::try_from(OsStr::new(src))
+    }
+}
+
+/// Fails if `src` contains a zero character
+impl TryFrom<&OsStr> for CPathBuf {
+    type Error = NulError;
+    fn try_from(src: &OsStr) -> Result<Self, NulError> {
+        Self::try_from(src.to_owned())
+    }
+}
+
+/// Fails if `src` contains a zero character
+impl TryFrom<OsString> for CPathBuf {
+    type Error = NulError;
+    fn try_from(src: OsString) -> Result<Self, NulError> {
+        CString::new(src.into_vec()).map(Self::from)
+    }
+}
+
+/// Fails if `src` contains a zero character
+impl TryFrom<&Path> for CPathBuf {
+    type Error = NulError;
+    fn try_from(src: &Path) -> Result<Self, NulError> {
+        Self::try_from(src.as_os_str())
+    }
+}
+
+/// Fails if `src` contains a zero character
+impl TryFrom<PathBuf> for CPathBuf {
+    type Error = NulError;
+    fn try_from(src: PathBuf) -> Result<Self, NulError> {
+        Self::try_from(src.into_os_string())
+    }
+}
+
+impl From<&CStr> for CPathBuf {
+    fn from(src: &CStr) -> Self {
+        CPath::new(src).to_owned()
+    }
+}
+
+impl From<CString> for CPathBuf {
+    fn from(src: CString) -> Self {
+        Self(src.into_bytes_with_nul().into_boxed_slice())
+    }
+}
+
+impl From<&CPath> for CPathBuf {
+    fn from(src: &CPath) -> Self {
+        src.to_owned()
     }
 }
 
 impl fmt::Debug for CPathBuf {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let p = String::from_utf8_lossy(&self.0);
-        f.write_str(&p)
+        fmt::Debug::fmt(self.as_c_path(), f)
     }
 }
 
-impl From<OsString> for CPathBuf {
-    fn from(src: OsString) -> Self {
-        src.into()
+impl fmt::Display for CPathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_c_path(), f)
     }
 }
-impl From<&Path> for CPathBuf {
-    fn from(src: &Path) -> Self {
-        Self::from(OsStr::new(src))
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> CPathBuf {
+        CPathBuf::try_from(path).unwrap()
+    }
+
+    fn components(p: &str) -> Vec<String> {
+        let p = path(p);
+        let to_string = |c: &OsStr| c.to_str().unwrap().to_owned();
+        let forward: Vec<_> = p.components().map(to_string).collect();
+        let mut backward: Vec<_> = p.components().rev().map(to_string).collect();
+        backward.reverse();
+        assert_eq!(forward, backward, "{p:?}");
+        forward
+    }
+
+    #[test]
+    fn components_like_path() {
+        for p in [
+            "",
+            "/",
+            "//",
+            ".",
+            "./",
+            "..",
+            "a",
+            "a/",
+            "a//b",
+            "/a/b/",
+            "./a/./b/.",
+            "a/../b",
+            "/.",
+            "./.",
+        ] {
+            let expected: Vec<_> = Path::new(p)
+                .components()
+                .map(|c| c.as_os_str().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(components(p), expected, "{p:?}");
+        }
+    }
+
+    #[test]
+    fn file_name_and_parent_like_path() {
+        for p in [
+            "", "/", ".", "..", "a", "a/", "a//b", "/a", "/a/b/", "a/.", "a/..", "./a", "//a",
+        ] {
+            let std = Path::new(p);
+            assert_eq!(path(p).file_name(), std.file_name(), "{p:?}");
+            assert_eq!(
+                path(p).parent().map(CPathBuf::into_path_buf),
+                std.parent().map(Path::to_path_buf),
+                "{p:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let mut p = path("");
+        p.push(c"a");
+        assert_eq!(p.as_slice(), b"a");
+        p.push(c"b");
+        assert_eq!(p.as_slice(), b"a/b");
+        let mut root = path("/");
+        root.push(c"usr");
+        assert_eq!(root.as_slice(), b"/usr");
+        assert!(root.pop());
+        assert_eq!(root.as_slice(), b"/");
+        assert!(!root.pop());
+        assert!(p.pop());
+        assert_eq!(p.as_slice(), b"a");
+        assert!(p.pop());
+        assert_eq!(p.as_slice(), b"");
+        assert!(!p.pop());
+        assert_eq!(path("a//b/").join(c"c").as_slice(), b"a//b/c");
+    }
+
+    #[test]
+    fn strip_prefix() {
+        let p = path("/usr//lib/x");
+        let strip = |base: &str| p.strip_prefix(&path(base)).map(|rest| rest.as_slice());
+        assert_eq!(strip("/usr"), Some(&b"lib/x"[..]));
+        assert_eq!(strip("/usr/lib/"), Some(&b"x"[..]));
+        assert_eq!(strip("/usr/lib/x"), Some(&b""[..]));
+        assert_eq!(strip("/"), Some(&b"usr//lib/x"[..]));
+        assert_eq!(strip("/us"), None);
+        assert_eq!(strip("usr"), None);
+        assert_eq!(p.strip_prefix(&path("/usr")).unwrap().to_bytes(), b"lib/x");
+    }
+
+    #[test]
+    fn conversions() {
+        let p = CPathBuf::try_from(OsString::from("a/b")).unwrap();
+        assert_eq!(p.as_c_str(), c"a/b");
+        assert_eq!(CPathBuf::try_from(PathBuf::from("a/b")).unwrap(), p);
+        assert_eq!(CPathBuf::try_from(Path::new("a/b")).unwrap(), p);
+        assert_eq!(CPathBuf::from(c"a/b"), p);
+        assert_eq!(CPathBuf::from(CString::from(c"a/b")), p);
+        assert_eq!(p.to_path(), Path::new("a/b"));
+        assert_eq!(p.clone().into_os_string(), "a/b");
+        assert_eq!(p.clone().into_c_string().as_c_str(), c"a/b");
+        assert_eq!(format!("{p} {p:?}"), "a/b \"a/b\"");
+        let invalid = CPathBuf::try_from(OsStr::from_bytes(b"\xff")).unwrap();
+        assert_eq!(invalid.to_string(), "\u{fffd}");
+    }
+
+    #[test]
+    fn interior_zero_character() {
+        let err = CPathBuf::try_from("a\0b").unwrap_err();
+        assert_eq!(err.nul_position(), 1);
+        assert!(CPathBuf::try_from(Path::new("a\0b")).is_err());
     }
 }
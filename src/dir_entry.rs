@@ -1,6 +1,6 @@
 use std::{
     ffi::{CStr, OsStr},
-    fmt,
+    fmt, io,
     marker::PhantomData,
     mem,
     os::unix::ffi::OsStrExt,
@@ -11,12 +11,12 @@ use crate::{buffer::Buffer, read_buf::ReadBuf};
 
 #[derive(Debug, Default)]
 #[repr(C)]
-struct linux_dirent {
+pub(crate) struct linux_dirent64 {
     /// inode number
     d_ino: libc::ino64_t,
-    /// offset to next linux_dirent
+    /// offset to next linux_dirent64
     d_off: libc::off64_t,
-    /// length of this linux_dirent
+    /// length of this linux_dirent64
     d_reclen: libc::c_ushort,
     /// file type
     d_type: libc::c_uchar,
@@ -24,17 +24,100 @@ struct linux_dirent {
     d_name: [libc::c_char; 1],
 }
 
-#[allow(dead_code)]
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Length of the largest record, the one of an entry with a name of `NAME_MAX` bytes
+pub(crate) const MAX_RECORD_LEN: usize =
+    (mem::offset_of!(linux_dirent64, d_name) + libc::NAME_MAX as usize + 1)
+        .next_multiple_of(mem::align_of::<linux_dirent64>());
+
+/// `d_type` of a whiteout entry on union filesystems (overlayfs, unionfs); not exported by `libc`
+const DT_WHT: u8 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum EntryType {
-    Unknown = libc::DT_UNKNOWN as isize,
-    Fifo = libc::DT_FIFO as isize,
-    CharDev = libc::DT_CHR as isize,
-    Dir = libc::DT_DIR as isize,
-    BlockDev = libc::DT_BLK as isize,
-    Regular = libc::DT_REG as isize,
-    Symlink = libc::DT_LNK as isize,
-    Socket = libc::DT_SOCK as isize,
+    Unknown,
+    Fifo,
+    CharDev,
+    Dir,
+    BlockDev,
+    Regular,
+    Symlink,
+    Socket,
+    Whiteout,
+    /// a `d_type` value this crate does not know about
+    Other(u8),
+}
+
+impl EntryType {
+    /// Convert `linux_dirent64::d_type`
+    pub fn from_d_type(d_type: u8) -> Self {
+        match d_type {
+            libc::DT_UNKNOWN => EntryType::Unknown,
+            libc::DT_FIFO => EntryType::Fifo,
+            libc::DT_CHR => EntryType::CharDev,
+            libc::DT_DIR => EntryType::Dir,
+            libc::DT_BLK => EntryType::BlockDev,
+            libc::DT_REG => EntryType::Regular,
+            libc::DT_LNK => EntryType::Symlink,
+            libc::DT_SOCK => EntryType::Socket,
+            DT_WHT => EntryType::Whiteout,
+            other => EntryType::Other(other),
+        }
+    }
+    /// Convert the file type bits of `stat::st_mode`
+    pub fn from_st_mode(mode: libc::mode_t) -> Self {
+        // same as `IFTODT` from <dirent.h>
+        Self::from_d_type(((mode & libc::S_IFMT) >> 12) as u8)
+    }
+    pub fn is_unknown(&self) -> bool {
+        *self == EntryType::Unknown
+    }
+    pub fn is_dir(&self) -> bool {
+        *self == EntryType::Dir
+    }
+    pub fn is_file(&self) -> bool {
+        *self == EntryType::Regular
+    }
+    pub fn is_symlink(&self) -> bool {
+        *self == EntryType::Symlink
+    }
+    pub fn is_fifo(&self) -> bool {
+        *self == EntryType::Fifo
+    }
+    pub fn is_socket(&self) -> bool {
+        *self == EntryType::Socket
+    }
+    pub fn is_char_device(&self) -> bool {
+        *self == EntryType::CharDev
+    }
+    pub fn is_block_device(&self) -> bool {
+        *self == EntryType::BlockDev
+    }
+    pub fn is_whiteout(&self) -> bool {
+        *self == EntryType::Whiteout
+    }
+}
+
+impl From<std::fs::FileType> for EntryType {
+    fn from(ty: std::fs::FileType) -> Self {
+        use std::os::unix::fs::FileTypeExt;
+        if ty.is_dir() {
+            EntryType::Dir
+        } else if ty.is_file() {
+            EntryType::Regular
+        } else if ty.is_symlink() {
+            EntryType::Symlink
+        } else if ty.is_fifo() {
+            EntryType::Fifo
+        } else if ty.is_socket() {
+            EntryType::Socket
+        } else if ty.is_char_device() {
+            EntryType::CharDev
+        } else if ty.is_block_device() {
+            EntryType::BlockDev
+        } else {
+            EntryType::Unknown
+        }
+    }
 }
 
 impl fmt::Display for EntryType {
@@ -49,6 +132,8 @@ impl fmt::Display for EntryType {
             Regular => "Regular",
             Symlink => "Symlink",
             Socket => "Socket",
+            Whiteout => "Whiteout",
+            Other(code) => return f.pad(&format!("Other({code})")),
         };
         f.pad(s)
     }
@@ -64,11 +149,15 @@ pub struct Entry<'a> {
 impl Entry<'_> {
     pub fn c_name(&self) -> &'_ CStr {
         // Safety
-        // the file names in the buffer obtained from kernel are zero-terminated
+        // `DirEntryIter` only yields names which are zero-terminated within their record
         unsafe { CStr::from_ptr(self.name.as_bytes().as_ptr().cast::<i8>()) }
     }
 }
 
+/// Parser of the `linux_dirent64` records filled in by `getdents64`
+///
+/// Every record is validated against the end of the buffer, a malformed one yields an error and
+/// ends the iteration.
 pub struct DirEntryIter<'a> {
     ptr: *const u8,
     end: *const u8,
@@ -79,6 +168,7 @@ impl<'a> DirEntryIter<'a> {
     pub fn new(buf: &'a Buffer) -> Self {
         let init = buf.init();
         let ptr = init.as_ptr();
+        debug_assert_eq!(ptr.align_offset(mem::align_of::<linux_dirent64>()), 0);
         Self {
             ptr,
             // Safety:
@@ -94,59 +184,242 @@ impl<'a> DirEntryIter<'a> {
             _p: PhantomData,
         }
     }
+    fn malformed(&mut self, what: &str) -> Option<io::Result<Entry<'a>>> {
+        // nothing after a malformed record can be trusted
+        self.ptr = self.end;
+        Some(Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("malformed linux_dirent64: {what}"),
+        )))
+    }
 }
 
 impl<'a> Iterator for DirEntryIter<'a> {
-    type Item = Entry<'a>;
+    type Item = io::Result<Entry<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
-        fn get_name(name: *const u8, len: usize) -> &'static OsStr {
-            let bytes = unsafe { slice::from_raw_parts(name, len) };
-            OsStr::from_bytes(bytes)
-        }
-        fn get_type(code: u8) -> EntryType {
-            unsafe { mem::transmute(code) }
-        }
+        const NAME_OFFSET: usize = mem::offset_of!(linux_dirent64, d_name);
 
         loop {
-            if self.ptr == self.end {
+            let remaining = self.end as usize - self.ptr as usize;
+            if remaining == 0 {
                 return None;
             }
-            let d = unsafe { &*self.ptr.cast::<linux_dirent>() };
-            self.ptr = unsafe { self.ptr.offset(d.d_reclen as isize) };
-
-            let mut name_ptr = d.d_name.as_ptr().cast::<u8>();
-            let add_len = unsafe {
-                match name_ptr.read() {
-                    0 => {
-                        return Some(Entry {
-                            inode: d.d_ino,
-                            name: get_name(d.d_name.as_ptr().cast::<u8>(), 0),
-                            ty: get_type(d.d_type),
-                        })
-                    }
-                    b'.' => {
-                        name_ptr = name_ptr.offset(1);
-                        match name_ptr.read() {
-                            0 => continue,
-                            b'.' => {
-                                name_ptr = name_ptr.offset(1);
-                                match name_ptr.read() {
-                                    0 => continue,
-                                    _ => 2,
-                                }
-                            }
-                            _ => 1,
-                        }
-                    }
-                    _ => 0,
-                }
+            if remaining < mem::size_of::<linux_dirent64>() {
+                return self.malformed("truncated record header");
+            }
+            // Safety:
+            // the buffer is aligned for `linux_dirent64` and every `d_reclen` is checked to be a
+            // multiple of its alignment, so `ptr` stays aligned and the header lies in the buffer
+            let d = unsafe { &*self.ptr.cast::<linux_dirent64>() };
+            let reclen = d.d_reclen as usize;
+            if reclen < mem::size_of::<linux_dirent64>()
+                || !reclen.is_multiple_of(mem::align_of::<linux_dirent64>())
+            {
+                return self.malformed("invalid d_reclen");
+            }
+            if reclen > remaining {
+                return self.malformed("d_reclen past the end of the buffer");
+            }
+            // Safety: the whole record was just checked to lie in the buffer
+            let record = unsafe { slice::from_raw_parts(self.ptr, reclen) };
+            self.ptr = unsafe { self.ptr.add(reclen) };
+
+            let name = &record[NAME_OFFSET..];
+            let name = match name.iter().position(|b| *b == 0) {
+                Some(len) => &name[..len],
+                None => return self.malformed("d_name is not terminated"),
             };
-            let name_len = unsafe { libc::strlen(name_ptr.cast::<i8>()) + add_len };
-            return Some(Entry {
+            if name == b"." || name == b".." {
+                continue;
+            }
+            return Some(Ok(Entry {
                 inode: d.d_ino,
-                name: get_name(d.d_name.as_ptr().cast::<u8>(), name_len),
-                ty: get_type(d.d_type),
-            });
+                name: OsStr::from_bytes(name),
+                ty: EntryType::from_d_type(d.d_type),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Encode one record the way the kernel does, padded to the alignment of `linux_dirent64`
+    pub(crate) fn dirent(inode: u64, d_type: u8, name: &[u8]) -> Vec<u8> {
+        let name_offset = mem::offset_of!(linux_dirent64, d_name);
+        let align = mem::align_of::<linux_dirent64>();
+        let reclen = (name_offset + name.len() + 1).next_multiple_of(align);
+        let mut rec = Vec::with_capacity(reclen);
+        rec.extend(inode.to_ne_bytes());
+        rec.extend(0i64.to_ne_bytes());
+        rec.extend((reclen as u16).to_ne_bytes());
+        rec.push(d_type);
+        rec.extend(name);
+        rec.resize(reclen, 0);
+        rec
+    }
+
+    fn parse(data: &[u8]) -> Vec<io::Result<(u64, EntryType, Vec<u8>)>> {
+        let buf = Buffer::from_slice(data);
+        DirEntryIter::new(&buf)
+            .map(|entry| entry.map(|e| (e.inode, e.ty, e.name.as_bytes().to_vec())))
+            .collect()
+    }
+
+    fn parse_ok(data: &[u8]) -> Vec<(u64, EntryType, Vec<u8>)> {
+        parse(data)
+            .into_iter()
+            .collect::<io::Result<_>>()
+            .expect("valid stream")
+    }
+
+    fn is_malformed(res: &io::Result<(u64, EntryType, Vec<u8>)>) -> bool {
+        matches!(res, Err(err) if err.kind() == io::ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn empty_buffer() {
+        assert!(parse(&[]).is_empty());
+    }
+
+    #[test]
+    fn skips_dot_and_dotdot() {
+        let mut data = dirent(1, libc::DT_DIR, b".");
+        data.extend(dirent(2, libc::DT_DIR, b".."));
+        data.extend(dirent(3, libc::DT_REG, b"file"));
+        assert_eq!(
+            parse_ok(&data),
+            vec![(3, EntryType::Regular, b"file".to_vec())]
+        );
+    }
+
+    #[test]
+    fn keeps_names_starting_with_dots() {
+        let names: [&[u8]; 4] = [b".x", b"..x", b"...", b".hidden"];
+        let data: Vec<u8> = names
+            .iter()
+            .enumerate()
+            .flat_map(|(i, name)| dirent(i as u64, libc::DT_REG, name))
+            .collect();
+        let expected: Vec<_> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (i as u64, EntryType::Regular, name.to_vec()))
+            .collect();
+        assert_eq!(parse_ok(&data), expected);
+    }
+
+    #[test]
+    fn empty_name() {
+        let data = dirent(7, libc::DT_UNKNOWN, b"");
+        assert_eq!(parse_ok(&data), vec![(7, EntryType::Unknown, Vec::new())]);
+    }
+
+    #[test]
+    fn max_length_name() {
+        let name = vec![b'n'; 255];
+        let mut data = dirent(1, libc::DT_REG, &name);
+        data.extend(dirent(2, libc::DT_DIR, b"after"));
+        assert_eq!(
+            parse_ok(&data),
+            vec![
+                (1, EntryType::Regular, name),
+                (2, EntryType::Dir, b"after".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn whiteout_and_unknown_types() {
+        let mut data = dirent(1, 14, b"wh");
+        data.extend(dirent(2, 200, b"other"));
+        assert_eq!(
+            parse_ok(&data),
+            vec![
+                (1, EntryType::Whiteout, b"wh".to_vec()),
+                (2, EntryType::Other(200), b"other".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn c_name_is_terminated() {
+        let data = dirent(1, libc::DT_REG, b"name");
+        let buf = Buffer::from_slice(&data);
+        let entry = DirEntryIter::new(&buf).next().unwrap().unwrap();
+        assert_eq!(entry.c_name().to_bytes(), b"name");
+    }
+
+    #[test]
+    fn zero_reclen_is_an_error() {
+        let mut data = dirent(1, libc::DT_REG, b"file");
+        data[16..18].copy_from_slice(&0u16.to_ne_bytes());
+        let res = parse(&data);
+        assert_eq!(res.len(), 1);
+        assert!(is_malformed(&res[0]));
+    }
+
+    #[test]
+    fn reclen_past_end_is_an_error() {
+        let mut data = dirent(1, libc::DT_REG, b"first");
+        data.extend(dirent(2, libc::DT_REG, b"second"));
+        data.truncate(data.len() - 8);
+        let res = parse(&data);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].as_ref().unwrap().2, b"first");
+        assert!(is_malformed(&res[1]));
+    }
+
+    #[test]
+    fn unterminated_name_is_an_error() {
+        let mut data = dirent(1, libc::DT_REG, b"abcd");
+        let len = data.len();
+        data[mem::offset_of!(linux_dirent64, d_name)..len].fill(b'x');
+        let res = parse(&data);
+        assert_eq!(res.len(), 1);
+        assert!(is_malformed(&res[0]));
+    }
+
+    #[test]
+    fn truncated_header_is_an_error() {
+        let data = dirent(1, libc::DT_REG, b"file");
+        let res = parse(&data[..12]);
+        assert_eq!(res.len(), 1);
+        assert!(is_malformed(&res[0]));
+    }
+
+    fn name() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(1u8.., 0..=255)
+    }
+
+    proptest! {
+        #[test]
+        fn yields_every_record(records in proptest::collection::vec((any::<u64>(), any::<u8>(), name()), 0..64)) {
+            let data: Vec<u8> = records
+                .iter()
+                .flat_map(|(inode, d_type, name)| dirent(*inode, *d_type, name))
+                .collect();
+            let expected: Vec<_> = records
+                .into_iter()
+                .filter(|(_, _, name)| name != b"." && name != b"..")
+                .map(|(inode, d_type, name)| (inode, EntryType::from_d_type(d_type), name))
+                .collect();
+            prop_assert_eq!(parse_ok(&data), expected);
+        }
+
+        #[test]
+        fn arbitrary_bytes_never_overrun(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let res = parse(&data);
+            // at most the last item is an error
+            if let Some((_, init)) = res.split_last() {
+                prop_assert!(init.iter().all(|res| res.is_ok()));
+            }
+            for (_, _, name) in res.into_iter().flatten() {
+                prop_assert!(name.len() < data.len());
+                prop_assert!(!name.contains(&0));
+            }
         }
     }
 }
@@ -1,15 +1,73 @@
-use std::io;
+use std::{error, fmt, io};
 
+use crate::cpathbuf::{CPath, CPathBuf};
+
+/// The operation which failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Open,
+    GetDents,
+    Close,
+    Stat,
+    /// Writing a path to a [`Sink`](crate::Sink)
+    Write,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Operation::Open => "open directory",
+            Operation::GetDents => "read directory",
+            Operation::Close => "close directory",
+            Operation::Stat => "stat",
+            Operation::Write => "write path",
+        };
+        f.pad(s)
+    }
+}
+
+/// An error encountered during a walk, with the path it happened on
 #[derive(Debug)]
-pub enum MyError {
-    Open(io::Error),
-    GetDEnts64(io::Error),
-    GetDirEntries(io::Error),
-    Close(io::Error),
-    FdOpenDir(io::Error),
-    ReadDir(io::Error),
-    OpenSubdir(io::Error),
-    USleep(io::Error),
-}
-
-pub type MyResult<T> = Result<T, MyError>;
+pub struct Error {
+    op: Operation,
+    path: CPathBuf,
+    source: io::Error,
+}
+
+impl Error {
+    pub fn new(op: Operation, path: CPathBuf, source: io::Error) -> Self {
+        Self { op, path, source }
+    }
+    pub fn operation(&self) -> Operation {
+        self.op
+    }
+    pub fn path(&self) -> &CPath {
+        &self.path
+    }
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+    pub fn kind(&self) -> io::ErrorKind {
+        self.source.kind()
+    }
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+    pub fn into_io_error(self) -> io::Error {
+        self.source
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot {} \"{}\": {}", self.op, self.path, self.source)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
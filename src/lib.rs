@@ -0,0 +1,21 @@
+pub mod arena;
+pub mod buffer;
+pub mod cpathbuf;
+pub mod dir_entry;
+pub mod error;
+mod output;
+mod pool;
+pub mod read_buf;
+pub mod shared_fd;
+mod sys;
+mod threads;
+mod walker;
+
+pub use cpathbuf::{CPath, CPathBuf};
+pub use error::{Error, Operation};
+pub use output::Sink;
+pub use pool::WalkPool;
+pub use threads::ThreadCount;
+pub use walker::{
+    BufferPolicy, DirEntry, DirEntryRef, ErrorPolicy, Walk, WalkStats, WalkSummary, Walker,
+};
@@ -1,180 +1,173 @@
-#![feature(generic_associated_types)]
-#![feature(maybe_uninit_slice)]
-
-pub mod buffer;
-pub mod cpathbuf;
-pub mod dir_entry;
-pub mod error;
-pub mod read_buf;
-pub mod shared_fd;
-
 use std::{
-    cmp, env,
-    ffi::CStr,
+    env,
+    ffi::OsString,
     io::{self, Write},
-    os::unix::io::RawFd,
-    path::Path,
-    thread,
+    os::unix::ffi::OsStrExt,
+    process,
 };
 
-use buffer::Buffer;
-use cpathbuf::CPathBuf;
-use dir_entry::{DirEntryIter, EntryType};
-use error::{MyError, MyResult};
-use flume::RecvError;
-use read_buf::ReadBuf;
-use shared_fd::SharedFd;
-use syscalls::{syscall3, Sysno};
+use recursive_dir_walk::{BufferPolicy, Sink, ThreadCount, Walker};
 
-unsafe fn getdents64(fd: RawFd, buf: &mut Buffer) -> Result<usize, MyError> {
-    let len = syscall3(
-        Sysno::getdents64,
-        fd as usize,
-        buf.data_mut().as_mut_ptr() as usize,
-        buf.len(),
+fn usage() -> String {
+    let default = match BufferPolicy::default() {
+        BufferPolicy::Fixed(size) => size.to_string(),
+        BufferPolicy::Adaptive { initial, max } => format!("{initial}..{max}"),
+    };
+    format!(
+        "\
+Usage: recursive_dir_walk [options] <root>
+
+Options:
+    --buffer-size <SIZE|INITIAL..MAX>
+        bytes read per getdents64 call, either fixed or doubling from INITIAL up to MAX
+        while a directory fills the buffer, between {min} and {max} [default: {default}]
+    --threads <N|auto>
+        worker threads, or `auto` to adjust them to the latency of the device during the walk
+        [default: detected from the CPUs and the device of the root]
+    --min-depth <N>
+        do not print entries less than N directories below the root, which is at depth 0
+    --max-depth <N>
+        do not descend more than N directories below the root",
+        min = BufferPolicy::MIN_SIZE,
+        max = BufferPolicy::MAX_SIZE,
     )
-    .map_err(|errno| MyError::GetDEnts64(io::Error::from_raw_os_error(errno.into_raw())))?;
-    buf.set_init_len(len);
-    Ok(len)
-}
-unsafe fn close(fd: RawFd) -> Result<(), MyError> {
-    let ret = libc::close(fd);
-    if ret < 0 {
-        return Err(MyError::Close(io::Error::from_raw_os_error(ret)));
-    }
-    Ok(())
-}
-unsafe fn openat64(path: &CStr) -> Result<RawFd, MyError> {
-    let ret = libc::openat64(
-        libc::AT_FDCWD,
-        path.as_ptr(),
-        libc::O_CLOEXEC | libc::O_NOATIME | libc::O_NOFOLLOW | libc::O_RDONLY | libc::O_DIRECTORY,
-    );
-    if ret < 1 {
-        return Err(MyError::OpenSubdir(io::Error::from_raw_os_error(ret)));
-    }
-    Ok(ret)
 }
 
-enum WorkRequest {
-    Open(CPathBuf),
-    ReadDir(CPathBuf, SharedFd),
-    Close(CPathBuf, RawFd),
+struct Args {
+    root: OsString,
+    buffer_policy: Option<BufferPolicy>,
+    threads: Option<ThreadCount>,
+    min_depth: usize,
+    max_depth: Option<usize>,
 }
-enum WorkResponse {
-    Open(CPathBuf, MyResult<SharedFd>),
-    ReadDir(CPathBuf, SharedFd, MyResult<Buffer>),
-    Close(CPathBuf, MyResult<()>),
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(size) if size < BufferPolicy::MIN_SIZE => Err(format!(
+            "buffer size {value} is below the minimum of {}",
+            BufferPolicy::MIN_SIZE
+        )),
+        Ok(size) if size > BufferPolicy::MAX_SIZE => Err(format!(
+            "buffer size {value} is above the maximum of {}",
+            BufferPolicy::MAX_SIZE
+        )),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("invalid buffer size {value:?}")),
+    }
 }
 
-fn worker(req_recv: flume::Receiver<WorkRequest>, res_send: flume::Sender<WorkResponse>) {
-    loop {
-        match req_recv.recv() {
-            Err(RecvError::Disconnected) => return,
-            Ok(WorkRequest::Open(path)) => {
-                let res = unsafe { openat64(&path) }.map(|fd| SharedFd::new(fd));
-                res_send.send(WorkResponse::Open(path, res)).unwrap();
-            }
-            Ok(WorkRequest::ReadDir(path, mut fd)) => {
-                let mut buf = Buffer::alloc(1024);
-                let res = unsafe {
-                    getdents64(fd.get().fd(), &mut buf).map(move |len| {
-                        buf.set_init_len(len);
-                        buf
-                    })
-                };
-                res_send.send(WorkResponse::ReadDir(path, fd, res)).unwrap();
+fn parse_buffer_policy(value: &str) -> Result<BufferPolicy, String> {
+    match value.split_once("..") {
+        None => Ok(BufferPolicy::Fixed(parse_size(value)?)),
+        Some((initial, max)) => {
+            let (initial, max) = (parse_size(initial)?, parse_size(max)?);
+            if initial > max {
+                return Err(format!("initial buffer size {initial} exceeds {max}"));
             }
-            Ok(WorkRequest::Close(path, fd)) => {
-                let res = unsafe { close(fd) };
-                res_send.send(WorkResponse::Close(path, res)).unwrap();
-            }
-        };
+            Ok(BufferPolicy::Adaptive { initial, max })
+        }
     }
 }
 
-pub fn read_dir_multi_thread<P: AsRef<Path>>(path: P) -> Result<(), MyError> {
-    const THREAD_COUNT: usize = 30;
-    let path = path.as_ref();
-    let mut in_progress = 1;
-    let mut max = 0;
-    let mut stdout = io::BufWriter::new(io::stdout().lock());
-    let (req_send, req_recv) = flume::unbounded();
-    let (res_send, res_recv) = flume::unbounded();
-
-    println!("{path:?}");
-    let root = WorkRequest::Open(CPathBuf::from(path));
-    req_send.send(root).unwrap();
-
-    let mut threads = Vec::with_capacity(THREAD_COUNT);
-    for _ in 0..THREAD_COUNT {
-        let req_recv = req_recv.clone();
-        let res_send = res_send.clone();
-        threads.push(thread::spawn(move || worker(req_recv, res_send)));
+fn parse_threads(value: &str) -> Result<ThreadCount, String> {
+    match value {
+        "auto" => Ok(ThreadCount::Auto),
+        _ => match value.parse() {
+            Ok(threads) if threads > 0 => Ok(ThreadCount::Fixed(threads)),
+            _ => Err(format!("invalid thread count {value:?}")),
+        },
     }
+}
 
-    loop {
-        if in_progress == 0 {
-            return Ok(());
-        }
-        let received = res_recv.recv().unwrap();
-        max = cmp::max(max, in_progress);
-        in_progress -= 1;
-        match received {
-            WorkResponse::Open(path, result) => match result {
-                Ok(fd) => {
-                    in_progress += 1;
-                    req_send.send(WorkRequest::ReadDir(path, fd)).unwrap();
-                }
-                Err(err) => {
-                    eprintln!("Error opening directory \"{path:?}\": {:?}", err);
-                }
+fn parse_depth(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid depth {value:?}"))
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut root = None;
+    let mut buffer_policy = None;
+    let mut threads = None;
+    let mut min_depth = 0;
+    let mut max_depth = None;
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.to_str() {
+            Some(arg) if arg.starts_with("--") => match arg.split_once('=') {
+                Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                None => (arg.to_owned(), None),
             },
-            WorkResponse::ReadDir(path, fd, buffer) => {
-                match buffer {
-                    Ok(mut buf) => {
-                        if buf.init().len() != 0 {
-                            in_progress += 1;
-                            req_send
-                                .send(WorkRequest::ReadDir(path.clone(), fd.clone()))
-                                .unwrap();
-                            for entry in DirEntryIter::new(&mut buf) {
-                                stdout.write(path.as_slice()).unwrap();
-                                stdout.write(b"\n").unwrap();
-                                if entry.ty == EntryType::Dir {
-                                    in_progress += 1;
-                                    req_send
-                                        .send(WorkRequest::Open(path.join(entry.c_name())))
-                                        .unwrap();
-                                } else if entry.ty != EntryType::Regular {
-                                    panic!("{entry:?}");
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        eprintln!("Error reading directory \"{path:?}\": {:?}", err);
-                    }
-                }
-                if let Some(raw_fd) = fd.release() {
-                    in_progress += 1;
-                    req_send.send(WorkRequest::Close(path, raw_fd)).unwrap()
-                }
-            }
-            WorkResponse::Close(path, result) => {
-                if let Err(err) = result {
-                    eprintln!("Error closing directory \"{path:?}\": {:?}", err);
-                }
+            _ if root.is_none() => {
+                root = Some(arg);
+                continue;
             }
+            _ => return Err("only one root can be walked".to_owned()),
+        };
+        let value = || {
+            value
+                .or_else(|| args.next().and_then(|value| value.into_string().ok()))
+                .ok_or_else(|| format!("{name} needs a value"))
+        };
+        match name.as_str() {
+            "--buffer-size" => buffer_policy = Some(parse_buffer_policy(&value()?)?),
+            "--threads" => threads = Some(parse_threads(&value()?)?),
+            "--min-depth" => min_depth = parse_depth(&value()?)?,
+            "--max-depth" => max_depth = Some(parse_depth(&value()?)?),
+            _ => return Err(format!("unknown option {name}")),
         }
     }
+    Ok(Args {
+        root: root.ok_or("missing root")?,
+        buffer_policy,
+        threads,
+        min_depth,
+        max_depth,
+    })
 }
 
 fn main() {
-    match env::args_os().skip(1).next() {
-        None => eprintln!("Usage: recursive_dir_walk <root>"),
-        Some(root) => {
-            read_dir_multi_thread(root).unwrap();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{}", usage());
+            process::exit(2)
+        }
+    };
+    if args.min_depth == 0 {
+        let mut stdout = io::stdout().lock();
+        let written = stdout
+            .write_all(args.root.as_bytes())
+            .and_then(|()| stdout.write_all(b"\n"))
+            .and_then(|()| stdout.flush());
+        match written {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => return,
+            Err(err) => {
+                eprintln!("cannot write path {:?}: {err}", args.root);
+                process::exit(1)
+            }
+        }
+    }
+    let mut walker = Walker::new(args.root).min_depth(args.min_depth);
+    if let Some(buffer_policy) = args.buffer_policy {
+        walker = walker.buffer_policy(buffer_policy);
+    }
+    if let Some(threads) = args.threads {
+        walker = walker.thread_count(threads);
+    }
+    if let Some(max_depth) = args.max_depth {
+        walker = walker.max_depth(max_depth);
+    }
+    // like `find`, report failure when any directory could not be walked
+    match walker.write_paths(Sink::Stdout) {
+        Ok(summary) if summary.stats.errors == 0 => {}
+        Ok(_) => process::exit(1),
+        // the reader went away, as with `| head`, nothing more is wanted
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1)
         }
     }
 }
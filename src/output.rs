@@ -0,0 +1,226 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsStr,
+    fs::File,
+    io::{self, Write},
+    mem::ManuallyDrop,
+    os::unix::{ffi::OsStrExt, io::FromRawFd},
+    sync::Mutex,
+    time::Duration,
+};
+
+use flume::{SendTimeoutError, TrySendError};
+
+use crate::{
+    cpathbuf::CPathBuf,
+    error::{Error, Operation, Result},
+    walker::{DirEntry, DirEntryRef},
+};
+
+/// Size of the per-worker buffer of a [`Sink`], flushed with a single write once full
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Where the workers deliver entries, and the errors the [`ErrorPolicy`] passes on
+///
+/// [`ErrorPolicy`]: crate::ErrorPolicy
+pub(crate) trait Output: Sync {
+    /// State owned by each worker
+    type Local: Send;
+    fn local(&self) -> Self::Local;
+    /// Deliver an entry, returns `false` once no more entries can be delivered, which aborts the
+    /// walk
+    fn entry(&self, local: &mut Self::Local, entry: DirEntryRef<'_>) -> bool;
+    fn error(&self, local: &mut Self::Local, err: Error);
+    /// Deliver what the worker held back, waiting a little for the consumer; returns whether
+    /// nothing is left
+    ///
+    /// Called before the worker takes its next job.
+    fn drain(&self, _local: &mut Self::Local) -> bool {
+        true
+    }
+    /// Called when the worker is done with the walk
+    fn finish(&self, _local: &mut Self::Local) {}
+}
+
+pub(crate) struct ForEach<F> {
+    f: F,
+    pub(crate) errors: Mutex<VecDeque<Error>>,
+}
+
+impl<F> ForEach<F> {
+    pub(crate) fn new(f: F) -> Self {
+        Self {
+            f,
+            errors: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl<F: Fn(DirEntryRef<'_>) + Sync> Output for ForEach<F> {
+    type Local = ();
+    fn local(&self) {}
+    fn entry(&self, _: &mut (), entry: DirEntryRef<'_>) -> bool {
+        (self.f)(entry);
+        true
+    }
+    fn error(&self, _: &mut (), err: Error) {
+        self.errors.lock().unwrap().push_back(err)
+    }
+}
+
+/// Sends entries to a [`Walk`](crate::Walk)
+///
+/// On threads of its own a worker waits for room in the channel. On a pool it holds entries
+/// back instead, at most the rest of the directory it reads, and gives its thread up to other
+/// walks while its consumer is behind.
+pub(crate) struct Channel {
+    send: flume::Sender<Result<DirEntry>>,
+    hold_back: bool,
+    /// Longest time [`Output::drain`] waits for room in the channel
+    timeout: Duration,
+}
+
+impl Channel {
+    pub(crate) fn new(
+        send: flume::Sender<Result<DirEntry>>,
+        hold_back: bool,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            send,
+            hold_back,
+            timeout,
+        }
+    }
+    /// Returns `false` once the `Walk` was dropped
+    fn send(&self, held: &mut VecDeque<Result<DirEntry>>, item: Result<DirEntry>) -> bool {
+        if !self.hold_back {
+            return self.send.send(item).is_ok();
+        }
+        if !held.is_empty() {
+            held.push_back(item);
+            return true;
+        }
+        match self.send.try_send(item) {
+            Ok(()) => true,
+            Err(TrySendError::Full(item)) => {
+                held.push_back(item);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+impl Output for Channel {
+    /// entries and errors held back while the channel is full
+    type Local = VecDeque<Result<DirEntry>>;
+    fn local(&self) -> Self::Local {
+        VecDeque::new()
+    }
+    fn entry(&self, held: &mut Self::Local, entry: DirEntryRef<'_>) -> bool {
+        self.send(held, Ok(entry.to_dir_entry()))
+    }
+    fn error(&self, held: &mut Self::Local, err: Error) {
+        self.send(held, Err(err));
+    }
+    fn drain(&self, held: &mut Self::Local) -> bool {
+        while let Some(item) = held.pop_front() {
+            match self.send.send_timeout(item, self.timeout) {
+                Ok(()) => {}
+                Err(SendTimeoutError::Timeout(item)) => {
+                    held.push_front(item);
+                    return false;
+                }
+                // the `Walk` was dropped
+                Err(SendTimeoutError::Disconnected(_)) => held.clear(),
+            }
+        }
+        true
+    }
+}
+
+type WriteChunk<'a> = dyn FnMut(&[u8]) -> io::Result<()> + Send + 'a;
+
+/// Destination of [`Walker::write_paths`](crate::Walker::write_paths)
+///
+/// Every worker collects whole lines in its own buffer and hands them over in chunks, so lines
+/// are never interleaved, but their order is not the order of any single directory.
+pub enum Sink<'a> {
+    /// The standard output of the process, written to directly rather than through the buffer
+    /// of [`io::Stdout`]
+    Stdout,
+    File(File),
+    /// Called with chunks of whole lines, one at a time
+    Callback(Box<WriteChunk<'a>>),
+}
+
+impl Sink<'_> {
+    fn write_all(&mut self, chunk: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Stdout => {
+                // the fd is borrowed, it must not be closed when done
+                let mut stdout =
+                    ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDOUT_FILENO) });
+                stdout.write_all(chunk)
+            }
+            Sink::File(file) => file.write_all(chunk),
+            Sink::Callback(f) => f(chunk),
+        }
+    }
+}
+
+/// Writes the path of every entry to a [`Sink`], one per line
+pub(crate) struct PathWriter<'a> {
+    sink: Mutex<Sink<'a>>,
+    /// the first failed write, after which the walk is aborted
+    pub(crate) failed: Mutex<Option<Error>>,
+    pub(crate) errors: Mutex<VecDeque<Error>>,
+}
+
+impl<'a> PathWriter<'a> {
+    pub(crate) fn new(sink: Sink<'a>) -> Self {
+        Self {
+            sink: Mutex::new(sink),
+            failed: Mutex::new(None),
+            errors: Mutex::new(VecDeque::new()),
+        }
+    }
+    fn flush(&self, buf: &mut Vec<u8>) -> bool {
+        if buf.is_empty() {
+            return true;
+        }
+        let result = self.sink.lock().unwrap().write_all(buf);
+        if let Err(err) = result {
+            // report the last path of the chunk, the write may have failed on any of them
+            let last = buf[..buf.len() - 1].rsplit(|&b| b == b'\n').next().unwrap();
+            let path =
+                CPathBuf::try_from(OsStr::from_bytes(last)).expect("paths have no zero character");
+            self.failed
+                .lock()
+                .unwrap()
+                .get_or_insert(Error::new(Operation::Write, path, err));
+            return false;
+        }
+        buf.clear();
+        true
+    }
+}
+
+impl Output for PathWriter<'_> {
+    type Local = Vec<u8>;
+    fn local(&self) -> Vec<u8> {
+        Vec::with_capacity(CHUNK_SIZE)
+    }
+    fn entry(&self, buf: &mut Vec<u8>, entry: DirEntryRef<'_>) -> bool {
+        entry.write_path(buf).unwrap();
+        buf.push(b'\n');
+        buf.len() < CHUNK_SIZE || self.flush(buf)
+    }
+    fn error(&self, _: &mut Vec<u8>, err: Error) {
+        self.errors.lock().unwrap().push_back(err)
+    }
+    fn finish(&self, buf: &mut Vec<u8>) {
+        self.flush(buf);
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::VecDeque,
+    fmt, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    error::Result,
+    output::Sink,
+    walker::{DirEntryRef, Walk, WalkSummary, Walker, Workers},
+};
+
+/// Runs on a thread of the pool until it returns `true`
+///
+/// A task returning `false` gave its thread up to the tasks waiting for one, and is queued again
+/// behind them.
+pub(crate) type Task = Box<dyn FnMut() -> bool + Send + 'static>;
+
+/// Threads running the workers of walks, kept between walks
+///
+/// Walks run one after another or at the same time; each takes at most as many threads as the
+/// pool has. While tasks of other walks wait for a thread, the workers of a walk give theirs up
+/// rather than wait, for a job or for the consumer of a [`Walk`], so that no walk waits for
+/// another to end. Dropping the pool waits for the walks still running and joins the threads.
+///
+/// ```no_run
+/// use recursive_dir_walk::{WalkPool, Walker};
+///
+/// let pool = WalkPool::new(16);
+/// for root in ["/usr", "/var"] {
+///     let summary = pool.for_each(&Walker::new(root), |entry| {
+///         println!("{:?}", entry.file_name());
+///     });
+/// }
+/// ```
+#[derive(Debug)]
+pub struct WalkPool {
+    tasks: Arc<TaskQueue>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl WalkPool {
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "at least one thread is required");
+        let tasks = Arc::new(TaskQueue::default());
+        let threads = (0..threads)
+            .map(|_| {
+                let tasks = tasks.clone();
+                thread::spawn(move || {
+                    while let Some(mut task) = tasks.pop() {
+                        // the walk the task belongs to reports the panic, the thread stays
+                        if let Ok(false) = panic::catch_unwind(AssertUnwindSafe(&mut task)) {
+                            tasks.push(task);
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self { tasks, threads }
+    }
+    /// Number of threads of the pool
+    pub fn threads(&self) -> usize {
+        self.threads.len()
+    }
+    /// Start a walk on the pool, see [`Walker::walk`]
+    pub fn walk(&self, walker: &Walker) -> Walk<'_> {
+        walker.walk_on(Workers::Pool(self))
+    }
+    /// Walk on the pool, calling `f` for every entry, see [`Walker::for_each`]
+    pub fn for_each<F: Fn(DirEntryRef<'_>) + Sync>(
+        &self,
+        walker: &Walker,
+        f: F,
+    ) -> Result<WalkSummary> {
+        walker.for_each_on(Workers::Pool(self), f)
+    }
+    /// Walk on the pool, writing every path to `sink`, see [`Walker::write_paths`]
+    pub fn write_paths(&self, walker: &Walker, sink: Sink<'_>) -> Result<WalkSummary> {
+        walker.write_paths_on(Workers::Pool(self), sink)
+    }
+    pub(crate) fn spawn(&self, task: Task) {
+        self.tasks.push(task);
+    }
+    pub(crate) fn tasks(&self) -> &Arc<TaskQueue> {
+        &self.tasks
+    }
+    /// Run `tasks` on the pool, returning once all of them are done
+    ///
+    /// Panics if one of them panicked.
+    pub(crate) fn scope<'s, F, I>(&self, tasks: I)
+    where
+        F: FnMut() -> bool + Send + 's,
+        I: IntoIterator<Item = F>,
+    {
+        let latch = Arc::new(Latch::default());
+        // waits for the tasks already queued even if the iterator or a spawn panics
+        let wait = Wait(&latch);
+        for mut task in tasks {
+            *latch.remaining.lock().unwrap() += 1;
+            let done = Done(latch.clone());
+            let task: Box<dyn FnMut() -> bool + Send + 's> = Box::new(move || {
+                let _unwinding = Unwinding(&done.0);
+                task()
+            });
+            // SAFETY: the borrows of the task outlive it, this function does not return or unwind
+            // before every task was dropped, which is once it is done or panicked
+            let task: Task = unsafe { mem::transmute(task) };
+            self.spawn(task);
+        }
+        drop(wait);
+        if *latch.panicked.lock().unwrap() {
+            panic!("a walk worker panicked");
+        }
+    }
+}
+
+impl Drop for WalkPool {
+    fn drop(&mut self) {
+        // the threads exit once the queued tasks are done
+        self.tasks.close();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Counts the tasks of [`WalkPool::scope`] still to be run
+#[derive(Default)]
+struct Latch {
+    remaining: Mutex<usize>,
+    done: Condvar,
+    panicked: Mutex<bool>,
+}
+
+/// Counts a task as done when dropped, which is once it is done or panicked
+struct Done(Arc<Latch>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        let mut remaining = self.0.remaining.lock().unwrap();
+        *remaining -= 1;
+        if *remaining == 0 {
+            self.0.done.notify_all();
+        }
+    }
+}
+
+/// Waits for every task counted by the latch to be done when dropped
+struct Wait<'a>(&'a Latch);
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        let mut remaining = self.0.remaining.lock().unwrap();
+        while *remaining > 0 {
+            remaining = self.0.done.wait(remaining).unwrap();
+        }
+    }
+}
+
+/// Records a panic of the task it was created in
+struct Unwinding<'a>(&'a Latch);
+
+impl Drop for Unwinding<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            *self.0.panicked.lock().unwrap() = true;
+        }
+    }
+}
+
+/// Tasks waiting for a thread of a [`WalkPool`]
+#[derive(Default)]
+pub(crate) struct TaskQueue {
+    queue: Mutex<Tasks>,
+    available: Condvar,
+    /// length of the queue, read by the workers without locking it
+    waiting: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Tasks {
+    tasks: VecDeque<Task>,
+    closed: bool,
+}
+
+impl TaskQueue {
+    fn push(&self, task: Task) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.tasks.push_back(task);
+        self.waiting.store(queue.tasks.len(), Ordering::Relaxed);
+        self.available.notify_one();
+    }
+    /// Wait for the next task, `None` once the queue is closed and empty
+    ///
+    /// A task still running may be queued again, by the thread running it, which then takes it
+    /// from the queue itself if no other thread is left.
+    fn pop(&self) -> Option<Task> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(task) = queue.tasks.pop_front() {
+                self.waiting.store(queue.tasks.len(), Ordering::Relaxed);
+                return Some(task);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.available.wait(queue).unwrap();
+        }
+    }
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.available.notify_all();
+    }
+    /// Whether tasks wait for a thread
+    pub(crate) fn has_waiting(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed) > 0
+    }
+}
+
+impl fmt::Debug for TaskQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskQueue")
+            .field("waiting", &self.waiting.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::atomic::AtomicBool, time::Duration};
+
+    #[test]
+    fn scope_waits_for_queued_tasks_when_unwinding() {
+        let pool = WalkPool::new(1);
+        let done = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope((0..2).map(|i| {
+                assert_eq!(i, 0, "iterator panicked");
+                let done = &done;
+                move || {
+                    thread::sleep(Duration::from_millis(50));
+                    done.store(true, Ordering::SeqCst);
+                    true
+                }
+            }))
+        }));
+        assert!(result.is_err());
+        assert!(done.load(Ordering::SeqCst));
+    }
+}
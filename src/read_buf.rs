@@ -1,7 +1,7 @@
 use std::{fmt, io, mem::MaybeUninit};
 
 pub trait Read2 {
-    fn read_buf<'me, 'f, B: ReadBuf>(&'me mut self, buf: &'f mut B) -> io::Result<usize>;
+    fn read_buf<B: ReadBuf>(&mut self, buf: &mut B) -> io::Result<usize>;
 }
 
 pub trait ReadBuf {
@@ -15,12 +15,33 @@ pub trait ReadBuf {
     fn data(&self) -> &[MaybeUninit<u8>];
     fn data_mut(&mut self) -> &mut [MaybeUninit<u8>];
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn init_len(&self) -> usize;
     fn uninit_len(&self) -> usize;
     fn clear(&mut self);
+    /// # Safety
+    ///
+    /// The first `len` bytes of the buffer must be initialized.
     unsafe fn set_init_len(&mut self, len: usize);
 }
 
+/// # Safety
+///
+/// Every byte of `data` must be initialized.
+unsafe fn assume_init_ref(data: &[MaybeUninit<u8>]) -> &[u8] {
+    // `MaybeUninit<u8>` has the same layout as `u8`
+    &*(data as *const [MaybeUninit<u8>] as *const [u8])
+}
+
+/// # Safety
+///
+/// Every byte of `data` must be initialized.
+unsafe fn assume_init_mut(data: &mut [MaybeUninit<u8>]) -> &mut [u8] {
+    &mut *(data as *mut [MaybeUninit<u8>] as *mut [u8])
+}
+
 pub struct ReadBuffer<'a> {
     data: &'a mut [MaybeUninit<u8>],
     initialized: usize,
@@ -38,14 +59,17 @@ impl fmt::Debug for ReadBuffer<'_> {
         f.debug_struct("ReadBuffer")
             .field("initialized", &self.initialized)
             .field("data", &unsafe {
-                MaybeUninit::slice_assume_init_ref(&self.data[0..self.initialized])
+                assume_init_ref(&self.data[0..self.initialized])
             })
             .finish()
     }
 }
 
 impl<'a> ReadBuf for ReadBuffer<'a> {
-    type SubReadBuf<'s> = SubReadBuffer<'s, Self> where Self: 's;
+    type SubReadBuf<'s>
+        = SubReadBuffer<'s, Self>
+    where
+        Self: 's;
     fn uninit(&mut self) -> Self::SubReadBuf<'_> {
         SubReadBuffer {
             offset: self.initialized,
@@ -54,10 +78,10 @@ impl<'a> ReadBuf for ReadBuffer<'a> {
         }
     }
     fn init(&self) -> &[u8] {
-        unsafe { MaybeUninit::slice_assume_init_ref(&self.data[0..self.initialized]) }
+        unsafe { assume_init_ref(&self.data[0..self.initialized]) }
     }
     fn init_mut(&mut self) -> &mut [u8] {
-        unsafe { MaybeUninit::slice_assume_init_mut(&mut self.data[0..self.initialized]) }
+        unsafe { assume_init_mut(&mut self.data[0..self.initialized]) }
     }
     fn data(&self) -> &[MaybeUninit<u8>] {
         self.data
@@ -89,7 +113,10 @@ pub struct SubReadBuffer<'a, T: ReadBuf> {
 }
 
 impl<T: ReadBuf> ReadBuf for SubReadBuffer<'_, T> {
-    type SubReadBuf<'s> = SubReadBuffer<'s, Self> where Self: 's;
+    type SubReadBuf<'s>
+        = SubReadBuffer<'s, Self>
+    where
+        Self: 's;
     fn uninit(&mut self) -> Self::SubReadBuf<'_> {
         SubReadBuffer {
             offset: self.parent.init_len() + self.initialized,
@@ -98,15 +125,11 @@ impl<T: ReadBuf> ReadBuf for SubReadBuffer<'_, T> {
         }
     }
     fn init(&self) -> &[u8] {
-        unsafe {
-            MaybeUninit::slice_assume_init_ref(
-                &self.parent.data()[self.offset..self.offset + self.initialized],
-            )
-        }
+        unsafe { assume_init_ref(&self.parent.data()[self.offset..self.offset + self.initialized]) }
     }
     fn init_mut(&mut self) -> &mut [u8] {
         unsafe {
-            MaybeUninit::slice_assume_init_mut(
+            assume_init_mut(
                 &mut self.parent.data_mut()[self.offset..self.offset + self.initialized],
             )
         }
@@ -1,81 +1,114 @@
 use std::{
-    mem,
-    os::unix::io::RawFd,
-    sync::atomic::{AtomicUsize, Ordering},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    sync::Arc,
 };
 
-pub struct FdWrap<'a> {
-    fd: RawFd,
-    uses: &'a mut AtomicUsize,
-}
+/// A directory fd shared by the jobs of its subdirectories, closed by its last owner
+///
+/// Owners give up their share with [`SharedFd::release`], which hands the fd to the last one
+/// so that it can close it and see the error. A share dropped instead of released closes the fd
+/// too if it was the last one, ignoring errors.
+#[derive(Debug, Clone)]
+pub struct SharedFd(Arc<OwnedFd>);
 
-impl FdWrap<'_> {
-    pub unsafe fn fd(&self) -> RawFd {
-        self.fd
+impl SharedFd {
+    pub fn new(fd: OwnedFd) -> Self {
+        Self(Arc::new(fd))
+    }
+    /// Give up this share of the fd, returning it if no other owner is left
+    ///
+    /// Exactly one of the owners releasing the fd concurrently gets it back.
+    pub fn release(self) -> Option<OwnedFd> {
+        Arc::into_inner(self.0)
     }
 }
 
-impl<'a> Drop for FdWrap<'a> {
-    fn drop(&mut self) {
-        let count = self.uses.fetch_sub(1, Ordering::SeqCst);
-        if count == 0 {
-            panic!("SharedFd count dropped to zero")
-        }
+impl From<OwnedFd> for SharedFd {
+    fn from(fd: OwnedFd) -> Self {
+        Self::new(fd)
     }
 }
 
-pub struct SharedFd {
-    fd: RawFd,
-    uses: *mut AtomicUsize,
+impl AsFd for SharedFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
 }
 
-impl SharedFd {
-    pub fn new(fd: RawFd) -> Self {
-        let b = Box::new(AtomicUsize::new(1));
-        let uses = Box::into_raw(b);
-        Self { fd, uses }
-    }
-    pub fn get(&mut self) -> FdWrap {
-        let uses = self.increment();
-        FdWrap { fd: self.fd, uses }
-    }
-    pub fn release(self) -> Option<RawFd> {
-        let me = mem::ManuallyDrop::new(self);
-        if me.decrement() == 1 {
-            Some(me.fd)
-        } else {
-            None
-        }
+impl AsRawFd for SharedFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
     }
-    fn increment(&self) -> &mut AtomicUsize {
-        let uses = unsafe { &mut *self.uses };
-        debug_assert!(uses.load(Ordering::SeqCst) < usize::MAX);
-        uses.fetch_add(1, Ordering::SeqCst);
-        uses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io, sync::Mutex, thread};
+
+    /// Held by every test, so that none reuses the number of an fd another expects closed
+    static FDS: Mutex<()> = Mutex::new(());
+
+    fn open() -> OwnedFd {
+        io::stdin().as_fd().try_clone_to_owned().unwrap()
     }
-    fn decrement(&self) -> usize {
-        let count = unsafe { &mut *self.uses }.fetch_sub(1, Ordering::SeqCst);
-        if count == 0 {
-            panic!("SharedFd count dropped to zero")
+
+    fn is_open(fd: RawFd) -> bool {
+        let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup < 0 {
+            assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EBADF));
+            return false;
         }
-        count
+        unsafe { libc::close(dup) };
+        true
     }
-}
 
-unsafe impl Send for SharedFd {}
+    #[test]
+    fn last_release_returns_fd() {
+        let _fds = FDS.lock().unwrap();
+        let fd = SharedFd::new(open());
+        let raw_fd = fd.as_raw_fd();
+        let (first, second) = (fd.clone(), fd.clone());
+        assert!(first.release().is_none());
+        assert!(fd.release().is_none());
+        assert!(is_open(raw_fd));
+        let owned = second.release().unwrap();
+        assert_eq!(owned.as_raw_fd(), raw_fd);
+        drop(owned);
+        assert!(!is_open(raw_fd));
+    }
 
-impl Clone for SharedFd {
-    fn clone(&self) -> Self {
-        self.increment();
-        Self {
-            fd: self.fd,
-            uses: self.uses,
-        }
+    #[test]
+    fn last_drop_closes() {
+        let _fds = FDS.lock().unwrap();
+        let fd = SharedFd::new(open());
+        let raw_fd = fd.as_raw_fd();
+        let child = fd.clone();
+        drop(fd);
+        assert!(is_open(raw_fd));
+        drop(child);
+        assert!(!is_open(raw_fd));
     }
-}
 
-impl Drop for SharedFd {
-    fn drop(&mut self) {
-        self.decrement();
+    #[test]
+    fn concurrent_release_returns_fd_once() {
+        let _fds = FDS.lock().unwrap();
+        let fd = SharedFd::new(open());
+        let raw_fd = fd.as_raw_fd();
+        let owners: Vec<_> = (0..8).map(|_| fd.clone()).collect();
+        drop(fd);
+        let released = thread::scope(|scope| {
+            let threads: Vec<_> = owners
+                .into_iter()
+                .map(|fd| scope.spawn(move || fd.release()))
+                .collect();
+            threads
+                .into_iter()
+                .filter_map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(released.len(), 1);
+        drop(released);
+        assert!(!is_open(raw_fd));
     }
 }
@@ -0,0 +1,149 @@
+use std::{
+    ffi::CStr,
+    io, mem,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{buffer::Buffer, read_buf::ReadBuf};
+use syscalls::{syscall3, syscall4, Errno, Sysno};
+
+pub(crate) fn getdents64(fd: BorrowedFd<'_>, buf: &mut Buffer) -> io::Result<usize> {
+    let len = unsafe {
+        syscall3(
+            Sysno::getdents64,
+            fd.as_raw_fd() as usize,
+            buf.data_mut().as_mut_ptr() as usize,
+            buf.len(),
+        )
+    }
+    .map_err(|errno| io::Error::from_raw_os_error(errno.into_raw()))?;
+    // the kernel initialized the first `len` bytes
+    unsafe { buf.set_init_len(len) };
+    Ok(len)
+}
+/// Close `fd`, reporting the error which dropping it would ignore
+pub(crate) fn close(fd: OwnedFd) -> io::Result<()> {
+    let ret = unsafe { libc::close(fd.into_raw_fd()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+fn open_flags(noatime: bool) -> libc::c_int {
+    let mut flags = libc::O_CLOEXEC | libc::O_NOFOLLOW | libc::O_RDONLY | libc::O_DIRECTORY;
+    if noatime {
+        flags |= libc::O_NOATIME;
+    }
+    flags
+}
+/// Open the directory `path`, relative to `dirfd` or else to the working directory
+pub(crate) fn openat64(
+    dirfd: Option<BorrowedFd<'_>>,
+    path: &CStr,
+    noatime: bool,
+) -> io::Result<OwnedFd> {
+    let flags = open_flags(noatime);
+    let dirfd = dirfd.map_or(libc::AT_FDCWD, |fd| fd.as_raw_fd());
+    let ret = unsafe { libc::openat64(dirfd, path.as_ptr(), flags) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(ret) })
+}
+/// `struct open_how` of `openat2`
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+/// Cleared once `openat2` is found missing, on kernels older than 5.6 or under a seccomp filter
+static OPENAT2: AtomicBool = AtomicBool::new(true);
+
+/// Open the directory `path` below `dirfd`, refusing to follow a symlink in any of its
+/// components
+///
+/// Without `openat2`, only the last component is checked, and a component replaced by a symlink
+/// during the open is followed.
+pub(crate) fn openat_beneath(
+    dirfd: BorrowedFd<'_>,
+    path: &CStr,
+    noatime: bool,
+) -> io::Result<OwnedFd> {
+    if OPENAT2.load(Ordering::Relaxed) {
+        let how = OpenHow {
+            flags: open_flags(noatime) as u64,
+            mode: 0,
+            resolve: RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS,
+        };
+        let ret = unsafe {
+            syscall4(
+                Sysno::openat2,
+                dirfd.as_raw_fd() as usize,
+                path.as_ptr() as usize,
+                &how as *const OpenHow as usize,
+                mem::size_of::<OpenHow>(),
+            )
+        };
+        match ret {
+            Ok(fd) => return Ok(unsafe { OwnedFd::from_raw_fd(fd as _) }),
+            Err(Errno::ENOSYS) => OPENAT2.store(false, Ordering::Relaxed),
+            Err(errno) => return Err(io::Error::from_raw_os_error(errno.into_raw())),
+        }
+    }
+    openat64(Some(dirfd), path, noatime)
+}
+pub(crate) fn fstatat(dirfd: BorrowedFd<'_>, name: &CStr) -> io::Result<libc::stat64> {
+    let mut stat = mem::MaybeUninit::<libc::stat64>::uninit();
+    let ret = unsafe {
+        libc::fstatat64(
+            dirfd.as_raw_fd(),
+            name.as_ptr(),
+            stat.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+/// Soft limit of open file descriptors, `None` if unlimited
+pub(crate) fn nofile_limit() -> io::Result<Option<u64>> {
+    let mut limit = mem::MaybeUninit::<libc::rlimit64>::uninit();
+    let ret = unsafe { libc::getrlimit64(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let limit = unsafe { limit.assume_init() };
+    Ok((limit.rlim_cur != libc::RLIM64_INFINITY).then_some(limit.rlim_cur))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::fd::AsFd, os::unix::fs::symlink, process};
+
+    #[test]
+    fn opens_beneath_without_following_symlinks() {
+        let root =
+            std::env::temp_dir().join(format!("recursive_dir_walk-beneath-{}", process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        symlink("a", root.join("link")).unwrap();
+        let dir = fs::File::open(&root).unwrap();
+
+        let opened = openat_beneath(dir.as_fd(), c"a/b", false).map(drop);
+        let through_link = openat_beneath(dir.as_fd(), c"link/b", false).map(drop);
+        let outside = openat_beneath(dir.as_fd(), c"/tmp", false).map(drop);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(opened.is_ok());
+        assert_eq!(through_link.unwrap_err().raw_os_error(), Some(libc::ELOOP));
+        assert_eq!(outside.unwrap_err().raw_os_error(), Some(libc::EXDEV));
+    }
+}
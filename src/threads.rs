@@ -0,0 +1,267 @@
+use std::{
+    ffi::CString,
+    fs,
+    mem::MaybeUninit,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    thread,
+    time::Instant,
+};
+
+/// Syscalls timed between two adjustments of the number of active workers
+const ADJUST_INTERVAL: u64 = 256;
+
+/// How many worker threads a walk runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThreadCount {
+    /// Always this many workers
+    Fixed(usize),
+    /// As many workers as suit the CPUs and the device the root is on
+    ///
+    /// Solid state and NVMe devices serve many requests at once and get a few workers per CPU,
+    /// rotational disks only a handful so that they don't seek back and forth between
+    /// directories. Network filesystems wait on round trips rather than CPUs and get at least
+    /// 16 workers, and other filesystems without a block device of their own (tmpfs, overlayfs,
+    /// btrfs subvolumes) as many as solid state devices, since they may sit on any device.
+    #[default]
+    Detect,
+    /// Start with as many active workers as [`ThreadCount::Detect`] and adjust their number
+    /// during the walk, between one and twice as many, from the latency of the syscalls
+    ///
+    /// Workers are added while the latency stays close to the lowest seen and removed once it
+    /// doubles, which means the device is saturated and queues the requests.
+    Auto,
+}
+
+impl ThreadCount {
+    /// Number of threads to spawn, and how many of them start active
+    pub(crate) fn resolve(&self, root: &Path) -> (usize, usize) {
+        match *self {
+            ThreadCount::Fixed(threads) => (threads, threads),
+            ThreadCount::Detect => {
+                let threads = detect(root);
+                (threads, threads)
+            }
+            ThreadCount::Auto => {
+                let threads = detect(root);
+                (threads * 2, threads)
+            }
+        }
+    }
+}
+
+/// What serves the syscalls of a walk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Device {
+    Rotational,
+    SolidState,
+    Network,
+    Other,
+}
+
+/// Filesystem magic numbers of `statfs` for NFS, SMB, CIFS, SMB2, Ceph, AFS, 9P, FUSE and Coda
+const NETWORK_FILESYSTEMS: [u32; 9] = [
+    0x6969,
+    0x517b,
+    0xff53_4d42,
+    0xfe53_4d42,
+    0x00c3_6400,
+    0x5346_414f,
+    0x0102_1997,
+    0x6573_5546,
+    0x7375_7245,
+];
+
+fn detect(root: &Path) -> usize {
+    let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    workers(cpus, device(root))
+}
+
+fn workers(cpus: usize, device: Device) -> usize {
+    match device {
+        Device::Rotational => cpus.min(4),
+        Device::SolidState | Device::Other => (cpus * 4).min(64),
+        Device::Network => (cpus * 8).clamp(16, 64),
+    }
+}
+
+fn device(root: &Path) -> Device {
+    let rotational = fs::metadata(root)
+        .ok()
+        .and_then(|meta| is_rotational(meta.dev()));
+    match rotational {
+        Some(true) => Device::Rotational,
+        Some(false) => Device::SolidState,
+        None if is_network(root) => Device::Network,
+        None => Device::Other,
+    }
+}
+
+/// Whether `root` is on a network filesystem, or one served by a userspace process
+fn is_network(root: &Path) -> bool {
+    let Ok(path) = CString::new(root.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    // the width and signedness of `f_type` vary between targets, the magic numbers fit in 32 bits
+    let magic = unsafe { stat.assume_init() }.f_type as u32;
+    NETWORK_FILESYSTEMS.contains(&magic)
+}
+
+/// Whether the block device `dev` is a spinning disk, `None` if it is not a block device
+fn is_rotational(dev: u64) -> Option<bool> {
+    let dir = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+    // partitions have no queue of their own, the disk they belong to is their parent
+    ["queue/rotational", "../queue/rotational"]
+        .iter()
+        .find_map(|file| fs::read_to_string(Path::new(&dir).join(file)).ok())
+        .map(|rotational| rotational.trim() == "1")
+}
+
+/// Number of workers allowed to take jobs, adjusted from the latency of their syscalls when
+/// enabled
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    enabled: bool,
+    active: AtomicUsize,
+    max: usize,
+    /// moving average of the syscall latency, in nanoseconds
+    latency: AtomicU64,
+    /// lowest moving average seen, which slowly follows it up
+    baseline: AtomicU64,
+    samples: AtomicU64,
+    peak_active: AtomicUsize,
+}
+
+impl Throttle {
+    pub(crate) fn new(enabled: bool, active: usize, max: usize) -> Self {
+        Self {
+            enabled,
+            active: AtomicUsize::new(active),
+            max,
+            latency: AtomicU64::new(0),
+            baseline: AtomicU64::new(u64::MAX),
+            samples: AtomicU64::new(0),
+            peak_active: AtomicUsize::new(active),
+        }
+    }
+    pub(crate) fn is_active(&self, worker: usize) -> bool {
+        worker < self.active.load(Ordering::Relaxed)
+    }
+    pub(crate) fn peak_active(&self) -> usize {
+        self.peak_active.load(Ordering::Relaxed)
+    }
+    /// Run a syscall, recording how long it took
+    pub(crate) fn time<T>(&self, syscall: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return syscall();
+        }
+        let start = Instant::now();
+        let result = syscall();
+        self.record(start.elapsed().as_nanos() as u64);
+        result
+    }
+    fn record(&self, sample: u64) {
+        // concurrent updates may lose a sample, which the average can do without
+        let latency = match self.latency.load(Ordering::Relaxed) {
+            0 => sample,
+            latency => latency - latency / 8 + sample / 8,
+        };
+        self.latency.store(latency, Ordering::Relaxed);
+        if self.samples.fetch_add(1, Ordering::Relaxed) % ADJUST_INTERVAL != ADJUST_INTERVAL - 1 {
+            return;
+        }
+        let baseline = match self.baseline.load(Ordering::Relaxed) {
+            baseline if baseline <= latency => baseline + (latency - baseline) / 64,
+            _ => latency,
+        };
+        self.baseline.store(baseline, Ordering::Relaxed);
+        let active = self.active.load(Ordering::Relaxed);
+        if latency > baseline * 2 && active > 1 {
+            self.active.store(active - 1, Ordering::Relaxed);
+        } else if latency < baseline + baseline / 2 && active < self.max {
+            self.active.store(active + 1, Ordering::Relaxed);
+            self.peak_active.fetch_max(active + 1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `intervals` adjustment intervals of syscalls taking `latency` nanoseconds
+    fn feed(throttle: &Throttle, latency: u64, intervals: u64) {
+        for _ in 0..intervals * ADJUST_INTERVAL {
+            throttle.record(latency);
+        }
+    }
+
+    #[test]
+    fn detects_workers_per_device() {
+        assert_eq!(workers(1, Device::Rotational), 1);
+        assert_eq!(workers(16, Device::Rotational), 4);
+        assert_eq!(workers(1, Device::SolidState), 4);
+        assert_eq!(workers(32, Device::SolidState), 64);
+        assert_eq!(workers(1, Device::Other), 4);
+        assert_eq!(workers(1, Device::Network), 16);
+        assert_eq!(workers(4, Device::Network), 32);
+        assert_eq!(workers(32, Device::Network), 64);
+    }
+
+    #[test]
+    fn auto_spawns_twice_the_detected_workers() {
+        let root = std::env::temp_dir();
+        let detected = detect(&root);
+        assert!(detected >= 1);
+        assert_eq!(ThreadCount::Detect.resolve(&root), (detected, detected));
+        assert_eq!(ThreadCount::Auto.resolve(&root), (detected * 2, detected));
+        assert_eq!(ThreadCount::Fixed(3).resolve(&root), (3, 3));
+    }
+
+    #[test]
+    fn adds_workers_while_latency_stays_low() {
+        let throttle = Throttle::new(true, 1, 4);
+        feed(&throttle, 1000, 1);
+        assert_eq!(throttle.active.load(Ordering::Relaxed), 2);
+        feed(&throttle, 1000, 4);
+        assert_eq!(throttle.active.load(Ordering::Relaxed), 4);
+        assert_eq!(throttle.peak_active(), 4);
+        assert!(throttle.is_active(3) && !throttle.is_active(4));
+    }
+
+    #[test]
+    fn keeps_workers_while_latency_rises_moderately() {
+        let throttle = Throttle::new(true, 2, 4);
+        feed(&throttle, 1000, 1);
+        assert_eq!(throttle.active.load(Ordering::Relaxed), 3);
+        feed(&throttle, 1800, 2);
+        assert_eq!(throttle.active.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn removes_workers_once_latency_doubles() {
+        let throttle = Throttle::new(true, 4, 8);
+        feed(&throttle, 1000, 1);
+        assert_eq!(throttle.active.load(Ordering::Relaxed), 5);
+        feed(&throttle, 10_000, 2);
+        assert_eq!(throttle.active.load(Ordering::Relaxed), 3);
+        feed(&throttle, 10_000, 8);
+        assert_eq!(throttle.active.load(Ordering::Relaxed), 1);
+        assert_eq!(throttle.peak_active(), 5);
+    }
+
+    #[test]
+    fn disabled_throttle_records_nothing() {
+        let throttle = Throttle::new(false, 2, 4);
+        for _ in 0..ADJUST_INTERVAL * 4 {
+            throttle.time(|| ());
+        }
+        assert_eq!(throttle.samples.load(Ordering::Relaxed), 0);
+        assert_eq!(throttle.active.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,1211 @@
+use std::{
+    collections::VecDeque,
+    ffi::{CStr, CString, OsStr},
+    io::{self, Write},
+    iter,
+    marker::PhantomData,
+    mem,
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as LocalQueue};
+
+use crate::{
+    arena::{LocalArena, Node, PathArena, PathBuffer},
+    buffer::{Buffer, BufferPool},
+    cpathbuf::{CPath, CPathBuf},
+    dir_entry::{linux_dirent64, DirEntryIter, Entry, EntryType, MAX_RECORD_LEN},
+    error::{Error, Operation, Result},
+    output::{Channel, ForEach, Output, PathWriter, Sink},
+    pool::{TaskQueue, WalkPool},
+    read_buf::ReadBuf,
+    shared_fd::SharedFd,
+    sys::{close, fstatat, getdents64, nofile_limit, openat64, openat_beneath},
+    threads::{ThreadCount, Throttle},
+};
+
+/// A directory waiting to be opened
+struct Job {
+    /// the directory in the arena of the walk, which outlives its jobs
+    node: Node<'static>,
+    /// the directory `node` is opened relative to; the root, and directories whose parent was
+    /// closed to stay within the fd budget, are opened by their full path
+    parent: Option<SharedFd>,
+}
+
+/// What a worker keeps to itself during a walk
+struct WorkerState<'s> {
+    queue: LocalQueue<Job>,
+    nodes: LocalArena<'s>,
+    paths: PathBuffer<'s>,
+}
+
+/// A worker of a walk, kept between the times it runs when it gives its thread up on a pool
+struct Worker<L> {
+    index: usize,
+    /// borrows the arena of the walk, which outlives its workers
+    state: WorkerState<'static>,
+    out: L,
+}
+
+/// What a walk does when a filesystem operation fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Print the error to stderr and carry on
+    #[default]
+    Continue,
+    /// Stop the walk and return the first error
+    Abort,
+    /// Carry on and return every error next to the entries
+    Collect,
+}
+
+/// How large the buffers passed to `getdents64` are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferPolicy {
+    /// Read every directory with buffers of this many bytes
+    Fixed(usize),
+    /// Start every directory with `initial` bytes and double the buffer after each read which
+    /// filled it, up to `max` bytes
+    ///
+    /// Small directories are read with small buffers, large ones with few syscalls.
+    Adaptive { initial: usize, max: usize },
+}
+
+impl BufferPolicy {
+    /// Smallest buffer size, which fits the record of an entry with a name of `NAME_MAX` bytes
+    ///
+    /// `getdents64` fails with `EINVAL` when the next record does not fit into the buffer.
+    pub const MIN_SIZE: usize = MAX_RECORD_LEN;
+    /// Largest buffer size, `i32::MAX` rounded down to the alignment of the records
+    ///
+    /// The kernel keeps the space left in the buffer in an `int`.
+    pub const MAX_SIZE: usize = i32::MAX as usize & !(mem::align_of::<linux_dirent64>() - 1);
+    fn initial(&self) -> usize {
+        match *self {
+            BufferPolicy::Fixed(size) => size,
+            BufferPolicy::Adaptive { initial, .. } => initial,
+        }
+    }
+    /// Size of the buffer for the next read, if it should be larger than `capacity` after a read
+    /// of `len` bytes
+    fn grow(&self, capacity: usize, len: usize) -> Option<usize> {
+        match *self {
+            BufferPolicy::Fixed(_) => None,
+            // the kernel stops filling the buffer once the next record does not fit
+            BufferPolicy::Adaptive { max, .. }
+                if capacity < max && len + MAX_RECORD_LEN > capacity =>
+            {
+                Some(capacity.saturating_mul(2).min(max))
+            }
+            BufferPolicy::Adaptive { .. } => None,
+        }
+    }
+}
+
+impl Default for BufferPolicy {
+    fn default() -> Self {
+        BufferPolicy::Adaptive {
+            initial: 1024,
+            max: 64 << 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    buffer_policy: BufferPolicy,
+    noatime: bool,
+}
+
+/// File descriptors left to the rest of the process when the budget of open directories is
+/// derived from `RLIMIT_NOFILE`
+const RESERVED_FDS: usize = 64;
+
+/// Default for [`Walker::queue_memory_limit`]
+const QUEUE_MEMORY_LIMIT: usize = 16 << 20;
+
+/// Entries a [`Walk`] buffers ahead of its consumer before the workers wait for it
+const WALK_CHANNEL_CAPACITY: usize = 4096;
+
+/// Times an idle worker yields before it goes to sleep until more work is queued
+const IDLE_SPINS: u32 = 16;
+
+/// Longest an idle worker sleeps without being woken up
+const IDLE_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// Where the workers of a walk run
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Workers<'p> {
+    /// threads spawned for the walk, which exit at its end
+    Spawned,
+    Pool(&'p WalkPool),
+}
+
+/// Builder for a parallel recursive directory walk
+///
+/// Only the root is opened by its path, every other directory is opened relative to its parent's
+/// fd, so the depth of the tree is not limited by `PATH_MAX`, unless the fd budget does not
+/// allow keeping the parent open, see [`Walker::max_open_fds`].
+///
+/// Each worker thread opens a directory, reads and parses it to the end and queues its
+/// subdirectories on its own deque, which idle workers steal from.
+#[derive(Debug, Clone)]
+pub struct Walker {
+    root: PathBuf,
+    threads: ThreadCount,
+    error_policy: ErrorPolicy,
+    max_open_fds: Option<usize>,
+    queue_memory_limit: usize,
+    min_depth: usize,
+    max_depth: usize,
+    config: Config,
+}
+
+impl Walker {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+            threads: ThreadCount::default(),
+            error_policy: ErrorPolicy::Continue,
+            max_open_fds: None,
+            queue_memory_limit: QUEUE_MEMORY_LIMIT,
+            min_depth: 0,
+            max_depth: usize::MAX,
+            config: Config {
+                buffer_policy: BufferPolicy::default(),
+                noatime: true,
+            },
+        }
+    }
+    /// Number of worker threads issuing syscalls
+    pub fn threads(self, threads: usize) -> Self {
+        self.thread_count(ThreadCount::Fixed(threads))
+    }
+    /// How many worker threads issue syscalls, detected from the CPUs and the device of the
+    /// root by default
+    pub fn thread_count(mut self, threads: ThreadCount) -> Self {
+        if let ThreadCount::Fixed(threads) = threads {
+            assert!(threads > 0, "at least one worker thread is required");
+        }
+        self.threads = threads;
+        self
+    }
+    /// Size in bytes of the buffer passed to each `getdents64` call, the same for every directory
+    ///
+    /// Panics below [`BufferPolicy::MIN_SIZE`] or above [`BufferPolicy::MAX_SIZE`].
+    pub fn buffer_size(self, buffer_size: usize) -> Self {
+        self.buffer_policy(BufferPolicy::Fixed(buffer_size))
+    }
+    /// How the buffers passed to `getdents64` are sized, adaptive from 1 KiB to 64 KiB by default
+    ///
+    /// Panics below [`BufferPolicy::MIN_SIZE`] or above [`BufferPolicy::MAX_SIZE`].
+    pub fn buffer_policy(mut self, buffer_policy: BufferPolicy) -> Self {
+        let (min_size, max_size) = (BufferPolicy::MIN_SIZE, BufferPolicy::MAX_SIZE);
+        match buffer_policy {
+            BufferPolicy::Fixed(size) => {
+                assert!(size >= min_size, "buffer size must be at least {min_size}");
+                assert!(size <= max_size, "buffer size must be at most {max_size}");
+            }
+            BufferPolicy::Adaptive { initial, max } => {
+                assert!(
+                    initial >= min_size,
+                    "buffer size must be at least {min_size}"
+                );
+                assert!(
+                    initial <= max,
+                    "initial buffer size must not exceed the maximum"
+                );
+                assert!(max <= max_size, "buffer size must be at most {max_size}");
+            }
+        }
+        self.config.buffer_policy = buffer_policy;
+        self
+    }
+    /// Open directories with `O_NOATIME`
+    ///
+    /// Directories not owned by the caller can't be opened with the flag; the first time that
+    /// happens the open is retried without it and the flag is dropped for the rest of the walk.
+    /// The flag is dropped for every directory, not only for those of the same owner or
+    /// filesystem: the walker does not know either before a directory is open.
+    pub fn noatime(mut self, noatime: bool) -> Self {
+        self.config.noatime = noatime;
+        self
+    }
+    /// What to do when opening, reading or closing a directory fails
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+    /// Maximum number of directories kept open at the same time
+    ///
+    /// Defaults to the `RLIMIT_NOFILE` soft limit, less a reserve for the rest of the process.
+    /// Opens over the budget wait until another directory is closed. Directories deeper than
+    /// `PATH_MAX` can only be opened relative to their parent, which may go over the budget by
+    /// up to one directory per worker.
+    ///
+    /// A subdirectory queued while the budget is used up does not keep its parent open. It is
+    /// opened by its path below the root, which stays open for the whole walk outside the
+    /// budget, with `openat2` refusing symlinks in any component. On kernels without
+    /// `openat2`, before 5.6, only the last component is checked: a directory replaced by a
+    /// symlink during the open is followed, even out of the tree.
+    pub fn max_open_fds(mut self, max_open_fds: usize) -> Self {
+        assert!(max_open_fds > 0, "at least one directory has to be open");
+        self.max_open_fds = Some(max_open_fds);
+        self
+    }
+    /// Approximate memory in bytes used by directories waiting to be opened before the walk
+    /// switches from breadth-first to depth-first
+    ///
+    /// Below the limit subdirectories go to a queue shared by all workers, above it to the
+    /// back of the worker's own deque, so that subtrees are finished before new ones are
+    /// started and the queue stops growing with the width of the tree. Defaults to 16 MiB.
+    ///
+    /// The memory counted includes the arena storing the paths of the directories, whose
+    /// names are freed once their subtree is done.
+    pub fn queue_memory_limit(mut self, bytes: usize) -> Self {
+        self.queue_memory_limit = bytes;
+        self
+    }
+    /// Depth below which entries are walked but not yielded, like `find -mindepth`
+    ///
+    /// The entries of the root are at depth 1, the root itself is never yielded.
+    pub fn min_depth(mut self, min_depth: usize) -> Self {
+        self.min_depth = min_depth;
+        self
+    }
+    /// Depth of the deepest entries yielded, like `find -maxdepth`
+    ///
+    /// Directories at that depth are yielded without being opened, so 0 walks nothing at all.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    /// Start the walk
+    ///
+    /// The root itself is not yielded, only the entries below it. Errors are yielded according
+    /// to the [`ErrorPolicy`]: never with `Continue`, as the last item with `Abort` and
+    /// interleaved with the entries with `Collect`.
+    pub fn walk(&self) -> Walk<'static> {
+        self.walk_on(Workers::Spawned)
+    }
+    /// Walk the tree, calling `f` for every entry below the root
+    ///
+    /// `f` is called from every worker thread at the same time. Unlike [`Walker::walk`] the
+    /// entries are borrowed straight from the `getdents64` buffers, so no allocation is made per
+    /// entry. With [`ErrorPolicy::Abort`] the first error is returned, with
+    /// [`ErrorPolicy::Collect`] all of them are in [`WalkSummary::errors`].
+    pub fn for_each<F: Fn(DirEntryRef<'_>) + Sync>(&self, f: F) -> Result<WalkSummary> {
+        self.for_each_on(Workers::Spawned, f)
+    }
+    /// Walk the tree, writing the path of every entry below the root to `sink`, one per line
+    ///
+    /// Each worker thread fills its own buffer and flushes it to the sink in one write once it
+    /// is full, so lines are never interleaved. A failed write aborts the walk and is returned,
+    /// whatever the [`ErrorPolicy`].
+    pub fn write_paths(&self, sink: Sink<'_>) -> Result<WalkSummary> {
+        self.write_paths_on(Workers::Spawned, sink)
+    }
+    pub(crate) fn walk_on<'p>(&self, workers: Workers<'p>) -> Walk<'p> {
+        let (shared, queues) = self.start(workers);
+        let (send, recv) = flume::bounded(WALK_CHANNEL_CAPACITY);
+        let mut threads = Vec::new();
+        for (index, queue) in queues.into_iter().enumerate() {
+            let output = Channel::new(
+                send.clone(),
+                matches!(workers, Workers::Pool(_)),
+                IDLE_TIMEOUT,
+            );
+            // Safety: the worker is dropped with the task, which owns a reference to `shared`
+            let mut worker = unsafe { shared.worker(index, queue, output.local()) };
+            let shared = shared.clone();
+            let task = move || shared.run(&mut worker, &output);
+            match workers {
+                Workers::Spawned => threads.push(thread::spawn(task)),
+                Workers::Pool(pool) => pool.spawn(Box::new(task)),
+            }
+        }
+        Walk {
+            shared,
+            recv,
+            threads,
+            finished: false,
+            workers: PhantomData,
+        }
+    }
+    pub(crate) fn for_each_on<F: Fn(DirEntryRef<'_>) + Sync>(
+        &self,
+        workers: Workers<'_>,
+        f: F,
+    ) -> Result<WalkSummary> {
+        let output = ForEach::new(f);
+        let shared = self.run_scoped(workers, &output);
+        shared.summary(output.errors.into_inner().unwrap())
+    }
+    pub(crate) fn write_paths_on(
+        &self,
+        workers: Workers<'_>,
+        sink: Sink<'_>,
+    ) -> Result<WalkSummary> {
+        let output = PathWriter::new(sink);
+        let shared = self.run_scoped(workers, &output);
+        if let Some(err) = output.failed.into_inner().unwrap() {
+            return Err(err);
+        }
+        shared.summary(output.errors.into_inner().unwrap())
+    }
+    /// Run the walk on threads borrowing `output`, returning once every directory was read and
+    /// every worker is done
+    fn run_scoped<O: Output>(&self, workers: Workers<'_>, output: &O) -> Arc<Shared> {
+        let (shared, queues) = self.start(workers);
+        let tasks = queues.into_iter().enumerate().map(|(index, queue)| {
+            let shared = &*shared;
+            // Safety: the worker is dropped with the task, before `shared`
+            let mut worker = unsafe { shared.worker(index, queue, output.local()) };
+            move || shared.run(&mut worker, output)
+        });
+        match workers {
+            Workers::Spawned => thread::scope(|scope| {
+                for task in tasks {
+                    scope.spawn(task);
+                }
+            }),
+            Workers::Pool(pool) => pool.scope(tasks),
+        }
+        shared
+    }
+    fn start(&self, workers: Workers<'_>) -> (Arc<Shared>, Vec<LocalQueue<Job>>) {
+        let (mut threads, mut active) = self.threads.resolve(&self.root);
+        if let Workers::Pool(pool) = workers {
+            threads = threads.min(pool.threads());
+            active = active.min(threads);
+        }
+        let queues: Vec<_> = (0..threads).map(|_| LocalQueue::new_lifo()).collect();
+
+        let max_open_fds = self.max_open_fds.unwrap_or_else(|| match nofile_limit() {
+            Ok(Some(limit)) => (limit as usize).saturating_sub(RESERVED_FDS).max(1),
+            Ok(None) | Err(_) => usize::MAX,
+        });
+
+        let root = CPathBuf::try_from(self.root.as_path());
+        let root_len = root.as_ref().map_or(0, |root| {
+            root.as_slice().len() + root.needs_separator() as usize
+        });
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: queues.iter().map(LocalQueue::stealer).collect(),
+            pending: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            queue_memory_limit: self.queue_memory_limit,
+            min_depth: self.min_depth,
+            max_depth: self.max_depth,
+            in_flight: AtomicUsize::new(0),
+            open_fds: AtomicUsize::new(0),
+            max_open_fds: AtomicUsize::new(max_open_fds),
+            threads,
+            throttle: Throttle::new(self.threads == ThreadCount::Auto, active, threads),
+            error_policy: self.error_policy,
+            buffer_policy: self.config.buffer_policy,
+            buffers: BufferPool::new(threads),
+            arena: PathArena::new(),
+            noatime: AtomicBool::new(self.config.noatime),
+            aborted: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            idle: Mutex::new(()),
+            wake: Condvar::new(),
+            pool_tasks: match workers {
+                Workers::Spawned => None,
+                Workers::Pool(pool) => Some(pool.tasks().clone()),
+            },
+            invalid_root: Mutex::new(None),
+            root_fd: OnceLock::new(),
+            root_len,
+            stats: Counters::default(),
+        });
+        if self.max_depth == 0 {
+            return (shared, queues);
+        }
+        let root = match root {
+            Ok(root) => root,
+            Err(err) => {
+                // the path of the error stops at the zero character
+                let pos = err.nul_position();
+                let mut path = err.into_vec();
+                path.truncate(pos);
+                let err = io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "path contains a zero character",
+                );
+                let path = CString::new(path).unwrap().into();
+                *shared.invalid_root.lock().unwrap() = Some(Error::new(Operation::Open, path, err));
+                return (shared, queues);
+            }
+        };
+        let root = shared.arena.root(&root);
+        shared.push(
+            Job {
+                // Safety: the arena is dropped with the jobs
+                node: unsafe { root.into_static() },
+                parent: None,
+            },
+            None,
+        );
+        (shared, queues)
+    }
+}
+
+/// A directory entry borrowed from the `getdents64` buffer it was read from
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntryRef<'a> {
+    parent: &'a CPath,
+    entry: &'a Entry<'a>,
+    depth: usize,
+}
+
+impl<'a> DirEntryRef<'a> {
+    /// Path of the directory containing this entry
+    pub fn parent(&self) -> &'a CPath {
+        self.parent
+    }
+    pub fn file_name(&self) -> &'a OsStr {
+        self.entry.name
+    }
+    pub fn inode(&self) -> libc::ino64_t {
+        self.entry.inode
+    }
+    pub fn file_type(&self) -> EntryType {
+        self.entry.ty
+    }
+    /// Number of directories between the root and the entry, 1 for the entries of the root
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+    /// Write the full path of the entry, without allocating it first
+    pub fn write_path<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(self.parent.as_slice())?;
+        if self.parent.needs_separator() {
+            w.write_all(b"/")?;
+        }
+        w.write_all(self.entry.name.as_bytes())
+    }
+    pub fn to_dir_entry(&self) -> DirEntry {
+        DirEntry {
+            path: self.parent.join(self.entry.c_name()),
+            inode: self.entry.inode,
+            ty: self.entry.ty,
+            depth: self.depth,
+        }
+    }
+}
+
+/// A directory entry yielded by [`Walk`]
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: CPathBuf,
+    inode: libc::ino64_t,
+    ty: EntryType,
+    depth: usize,
+}
+
+impl DirEntry {
+    pub fn path(&self) -> &CPath {
+        &self.path
+    }
+    pub fn into_path(self) -> CPathBuf {
+        self.path
+    }
+    pub fn inode(&self) -> libc::ino64_t {
+        self.inode
+    }
+    pub fn file_type(&self) -> EntryType {
+        self.ty
+    }
+    /// Number of directories between the root and the entry, 1 for the entries of the root
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+/// Counters collected during a walk
+#[derive(Debug, Clone, Default)]
+pub struct WalkStats {
+    /// Directories opened to read their entries
+    pub dirs_read: u64,
+    /// Entries without a `d_type` whose type had to be looked up with `fstatat`
+    pub stat_fallbacks: u64,
+    /// Opens retried without `O_NOATIME` after failing with `EPERM`
+    pub noatime_retries: u64,
+    /// Subdirectories queued without keeping their parent open, to stay within the fd budget
+    pub spilled_opens: u64,
+    /// Opens requeued after failing with `EMFILE`
+    pub emfile_requeues: u64,
+    /// Failed filesystem operations, whatever the [`ErrorPolicy`]
+    pub errors: u64,
+    /// Most directories waiting to be opened at the same time
+    pub peak_queued: usize,
+    /// Most memory in bytes taken by directories waiting to be opened at the same time, with
+    /// the paths of the directories whose subtree is not done yet
+    pub peak_queued_bytes: usize,
+    /// Most directories being opened or read at the same time
+    pub peak_in_flight: usize,
+    /// `getdents64` buffers allocated because none large enough was pooled
+    pub buffer_allocations: u64,
+    /// `getdents64` buffers reused from the pool
+    pub buffer_reuses: u64,
+    /// Most workers taking jobs at the same time, which only varies with [`ThreadCount::Auto`]
+    pub peak_active_workers: usize,
+    /// Most memory in bytes allocated at the same time to store the paths of directories
+    pub peak_path_arena_bytes: usize,
+}
+
+/// Outcome of a walk which was not aborted
+#[derive(Debug, Default)]
+pub struct WalkSummary {
+    pub stats: WalkStats,
+    /// Every error of the walk with [`ErrorPolicy::Collect`], empty otherwise
+    pub errors: Vec<Error>,
+}
+
+/// [`WalkStats`] updated by all the workers at once
+#[derive(Default)]
+struct Counters {
+    dirs_read: AtomicU64,
+    stat_fallbacks: AtomicU64,
+    noatime_retries: AtomicU64,
+    spilled_opens: AtomicU64,
+    emfile_requeues: AtomicU64,
+    errors: AtomicU64,
+    peak_queued: AtomicUsize,
+    peak_queued_bytes: AtomicUsize,
+    peak_in_flight: AtomicUsize,
+}
+
+impl Counters {
+    fn snapshot(&self, buffers: &BufferPool, throttle: &Throttle, arena: &PathArena) -> WalkStats {
+        WalkStats {
+            dirs_read: self.dirs_read.load(Ordering::Relaxed),
+            stat_fallbacks: self.stat_fallbacks.load(Ordering::Relaxed),
+            noatime_retries: self.noatime_retries.load(Ordering::Relaxed),
+            spilled_opens: self.spilled_opens.load(Ordering::Relaxed),
+            emfile_requeues: self.emfile_requeues.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            peak_queued: self.peak_queued.load(Ordering::Relaxed),
+            peak_queued_bytes: self.peak_queued_bytes.load(Ordering::Relaxed),
+            peak_in_flight: self.peak_in_flight.load(Ordering::Relaxed),
+            buffer_allocations: buffers.allocations(),
+            buffer_reuses: buffers.reuses(),
+            peak_active_workers: throttle.peak_active(),
+            peak_path_arena_bytes: arena.peak_bytes(),
+        }
+    }
+}
+
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Look up the type of an entry read without a `d_type` in the directory `dir`
+///
+/// The entry stays `Unknown` if the lookup fails.
+fn resolve_type(dir: BorrowedFd<'_>, entry: &mut Entry<'_>, stats: &Counters) -> io::Result<()> {
+    bump(&stats.stat_fallbacks);
+    let stat = fstatat(dir, entry.c_name())?;
+    entry.ty = EntryType::from_st_mode(stat.st_mode);
+    Ok(())
+}
+
+/// State of a walk shared by its workers
+struct Shared {
+    /// subdirectories queued while below `queue_memory_limit`, taken in the order they were
+    /// found
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    /// jobs queued or being processed, the walk is over once it drops to zero; a job is only
+    /// finished after its subdirectories were queued
+    pending: AtomicUsize,
+    queued: AtomicUsize,
+    queue_memory_limit: usize,
+    /// entries shallower are not yielded, directories as deep are not opened
+    min_depth: usize,
+    max_depth: usize,
+    in_flight: AtomicUsize,
+    /// directories opened and not closed yet, including the ones held open by queued jobs
+    open_fds: AtomicUsize,
+    max_open_fds: AtomicUsize,
+    threads: usize,
+    throttle: Throttle,
+    error_policy: ErrorPolicy,
+    buffer_policy: BufferPolicy,
+    buffers: BufferPool,
+    arena: PathArena,
+    /// whether new directories are opened with `O_NOATIME`
+    noatime: AtomicBool,
+    /// set on the first error with `ErrorPolicy::Abort` or when the `Walk` is dropped, from then
+    /// on the workers only close what is still open
+    aborted: AtomicBool,
+    sleepers: AtomicUsize,
+    idle: Mutex<()>,
+    wake: Condvar,
+    /// the tasks of the pool the walk runs on, which its workers give their threads up to
+    /// rather than wait
+    pool_tasks: Option<Arc<TaskQueue>>,
+    /// the error of a root which cannot be passed to `open`, reported by the first worker to run
+    invalid_root: Mutex<Option<Error>>,
+    /// a duplicate of the root, which directories queued without their parent are opened below
+    root_fd: OnceLock<OwnedFd>,
+    /// length of the path of the root, with the separator of its entries
+    root_len: usize,
+    stats: Counters,
+}
+
+impl Shared {
+    fn summary(&self, mut errors: VecDeque<Error>) -> Result<WalkSummary> {
+        if self.aborted.load(Ordering::SeqCst) {
+            return Err(errors.pop_front().unwrap());
+        }
+        Ok(WalkSummary {
+            stats: self
+                .stats
+                .snapshot(&self.buffers, &self.throttle, &self.arena),
+            errors: errors.into(),
+        })
+    }
+    /// Safety: the worker must not be used once `self` is dropped
+    unsafe fn worker<L>(&self, index: usize, queue: LocalQueue<Job>, out: L) -> Worker<L> {
+        Worker {
+            index,
+            state: WorkerState {
+                queue,
+                nodes: self.arena.local().into_static(),
+                paths: PathBuffer::new(),
+            },
+            out,
+        }
+    }
+    /// Process jobs until every directory of the walk has been read, returns `false` if the worker gave its thread up to
+    /// other tasks of the pool first and has to be run again
+    fn run<O: Output>(&self, worker: &mut Worker<O::Local>, output: &O) -> bool {
+        if let Some(err) = self.invalid_root.lock().unwrap().take() {
+            self.error(err, output, &mut worker.out);
+        }
+        let mut idle = 0;
+        loop {
+            // what was held back for the consumer is delivered before the next directory is read
+            if !output.drain(&mut worker.out) {
+                match self.should_yield() {
+                    true => return false,
+                    false => continue,
+                }
+            }
+            // the jobs left on the deque of an inactive worker are stolen by the active ones
+            let job = match self.throttle.is_active(worker.index) {
+                true => self.find_job(&worker.state.queue),
+                false => None,
+            };
+            match job {
+                Some(job) => {
+                    idle = 0;
+                    self.process(job, &mut worker.state, output, &mut worker.out);
+                }
+                None if self.pending.load(Ordering::Acquire) == 0 => break,
+                None if idle >= IDLE_SPINS && self.should_yield() => return false,
+                None => {
+                    self.wait(idle);
+                    idle += 1;
+                }
+            }
+        }
+        output.finish(&mut worker.out);
+        true
+    }
+    /// Whether tasks of the pool the walk runs on wait for a thread
+    fn should_yield(&self) -> bool {
+        self.pool_tasks
+            .as_ref()
+            .is_some_and(|tasks| tasks.has_waiting())
+    }
+    fn find_job(&self, local: &LocalQueue<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+    fn wait(&self, idle: u32) {
+        if idle < IDLE_SPINS {
+            thread::yield_now();
+            return;
+        }
+        // a job queued right before the sleeper is registered is not announced, the timeout
+        // bounds how long it waits for it
+        let guard = self.idle.lock().unwrap();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        drop(self.wake.wait_timeout(guard, IDLE_TIMEOUT).unwrap());
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+    /// Queue a directory, on the shared queue below the memory limit and at the back of the
+    /// worker's deque above it
+    fn push(&self, job: Job, local: Option<&LocalQueue<Job>>) {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        let queued_bytes = queued * mem::size_of::<Job>() + self.arena.bytes();
+        self.stats.peak_queued.fetch_max(queued, Ordering::Relaxed);
+        self.stats
+            .peak_queued_bytes
+            .fetch_max(queued_bytes, Ordering::Relaxed);
+        match local {
+            Some(local) if queued_bytes > self.queue_memory_limit => local.push(job),
+            _ => self.injector.push(job),
+        }
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            self.wake.notify_one();
+        }
+    }
+    fn process<O: Output>(
+        &self,
+        job: Job,
+        state: &mut WorkerState<'_>,
+        output: &O,
+        out: &mut O::Local,
+    ) {
+        let _finished = Finished(self);
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        let Job { node, parent } = job;
+        if self.aborted.load(Ordering::SeqCst) {
+            if let Some(parent) = parent {
+                self.release_parent(node, parent, output, out);
+            }
+            // Safety: the reference of the job, which is done
+            unsafe { self.arena.release(node) };
+            return;
+        }
+        if !self.reserve_fd(parent.is_some()) {
+            // other workers are reading directories, wait for one of them to be closed
+            self.push(Job { node, parent }, None);
+            thread::yield_now();
+            return;
+        }
+        let fd = match self.open(node, parent.as_ref(), &mut state.paths) {
+            Ok(fd) => fd,
+            Err(err) => {
+                let open_fds = self.open_fds.fetch_sub(1, Ordering::SeqCst) - 1;
+                if err.raw_os_error() == Some(libc::EMFILE) && open_fds > parent.is_some() as usize
+                {
+                    // something else in the process holds fds too, try again once a directory
+                    // of this walk was closed and never keep more open than now
+                    bump(&self.stats.emfile_requeues);
+                    self.max_open_fds
+                        .fetch_min(open_fds.max(1), Ordering::SeqCst);
+                    let parent = match parent {
+                        Some(parent) if is_short(node) => {
+                            self.release_parent(node, parent, output, out);
+                            None
+                        }
+                        parent => parent,
+                    };
+                    self.push(Job { node, parent }, None);
+                    return;
+                }
+                if let Some(parent) = parent {
+                    self.release_parent(node, parent, output, out);
+                }
+                self.error(
+                    Error::new(Operation::Open, node.to_path_buf(), err),
+                    output,
+                    out,
+                );
+                // Safety: the reference of the job, which is done
+                unsafe { self.arena.release(node) };
+                return;
+            }
+        };
+        if let Some(parent) = parent {
+            self.release_parent(node, parent, output, out);
+        }
+        bump(&self.stats.dirs_read);
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.stats
+            .peak_in_flight
+            .fetch_max(in_flight, Ordering::Relaxed);
+        if node.depth() == 0 {
+            // without it, directories queued without their parent are opened by their full path
+            if let Ok(root) = fd.try_clone() {
+                let _ = self.root_fd.set(root);
+            }
+        }
+        let fd = SharedFd::new(fd);
+        match self.buffers.take(self.buffer_policy.initial()) {
+            Ok(mut buf) => {
+                self.read_dir(node, &fd, state, &mut buf, output, out);
+                self.buffers.put(buf);
+            }
+            Err(err) => self.error(
+                Error::new(Operation::GetDents, node.to_path_buf(), err),
+                output,
+                out,
+            ),
+        }
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.release(node, fd, output, out);
+        // Safety: the reference of the job, which is done, its subdirectories hold their own
+        unsafe { self.arena.release(node) };
+    }
+    /// Take a slot of the fd budget for an open, always granted to directories opened relative
+    /// to their parent: they release it once open, and waiting for the budget while holding the
+    /// parent could leave every slot held by a waiting job
+    fn reserve_fd(&self, has_parent: bool) -> bool {
+        if has_parent {
+            self.open_fds.fetch_add(1, Ordering::SeqCst);
+            return true;
+        }
+        let mut open_fds = self.open_fds.load(Ordering::SeqCst);
+        while open_fds < self.max_open_fds.load(Ordering::SeqCst) {
+            match self.open_fds.compare_exchange_weak(
+                open_fds,
+                open_fds + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(current) => open_fds = current,
+            }
+        }
+        false
+    }
+    /// Open the directory of `node`, relative to its parent if it has one, without `O_NOATIME`
+    /// from now on if it is refused
+    fn open<'s>(
+        &self,
+        node: Node<'s>,
+        parent: Option<&SharedFd>,
+        paths: &mut PathBuffer<'s>,
+    ) -> io::Result<OwnedFd> {
+        let noatime = self.noatime.load(Ordering::Relaxed);
+        // only a single path component is resolved by the kernel, except for the root and the
+        // directories which could not keep their parent open
+        let root = self.root_fd.get().filter(|_| node.depth() > 0);
+        let (dirfd, path) = match (parent, root) {
+            (Some(parent), _) => (Some(parent.as_fd()), node.name()),
+            (None, Some(root)) => {
+                let path = paths.resolve(node).as_c_str().to_bytes_with_nul();
+                // Safety: a suffix of the path, with its zero character
+                let path = unsafe { CStr::from_bytes_with_nul_unchecked(&path[self.root_len..]) };
+                (Some(root.as_fd()), path)
+            }
+            (None, None) => (None, paths.resolve(node).as_c_str()),
+        };
+        let open = |noatime| {
+            self.throttle.time(|| match (parent, dirfd) {
+                (None, Some(root)) => openat_beneath(root, path, noatime),
+                _ => openat64(dirfd, path, noatime),
+            })
+        };
+        let res = open(noatime);
+        // `O_NOATIME` is only permitted to the owner of the directory
+        if noatime && matches!(&res, Err(err) if err.raw_os_error() == Some(libc::EPERM)) {
+            bump(&self.stats.noatime_retries);
+            self.noatime.store(false, Ordering::Relaxed);
+            return open(false);
+        }
+        res
+    }
+    /// Read the directory to the end, queueing its subdirectories and passing every entry to
+    /// `output`
+    fn read_dir<'s, O: Output>(
+        &self,
+        node: Node<'s>,
+        fd: &SharedFd,
+        state: &mut WorkerState<'s>,
+        buf: &mut Buffer,
+        output: &O,
+        out: &mut O::Local,
+    ) {
+        let WorkerState {
+            queue,
+            nodes,
+            paths,
+        } = state;
+        let path = paths.resolve(node);
+        let depth = node.depth() + 1;
+        loop {
+            let len = match self.throttle.time(|| getdents64(fd.as_fd(), buf)) {
+                Ok(0) => return,
+                Ok(len) => len,
+                Err(err) => {
+                    self.error(
+                        Error::new(Operation::GetDents, path.to_owned(), err),
+                        output,
+                        out,
+                    );
+                    return;
+                }
+            };
+            for entry in DirEntryIter::new(buf) {
+                let mut entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        self.error(
+                            Error::new(Operation::GetDents, path.to_owned(), err),
+                            output,
+                            out,
+                        );
+                        break;
+                    }
+                };
+                if entry.ty.is_unknown() {
+                    // the filesystem does not fill `d_type`
+                    let resolved = self
+                        .throttle
+                        .time(|| resolve_type(fd.as_fd(), &mut entry, &self.stats));
+                    if let Err(err) = resolved {
+                        self.error(
+                            Error::new(Operation::Stat, path.join(entry.c_name()), err),
+                            output,
+                            out,
+                        );
+                    }
+                }
+                if self.aborted.load(Ordering::SeqCst) {
+                    return;
+                }
+                // symlinks are never followed, so only real directories are descended into
+                if entry.ty.is_dir() && depth < self.max_depth {
+                    let child = nodes.push(node, entry.c_name());
+                    let parent = self.child_parent(child, fd);
+                    self.push(
+                        Job {
+                            // Safety: the arena is dropped with the jobs
+                            node: unsafe { child.into_static() },
+                            parent,
+                        },
+                        Some(queue),
+                    );
+                }
+                if depth < self.min_depth {
+                    continue;
+                }
+                let entry = DirEntryRef {
+                    parent: path,
+                    entry: &entry,
+                    depth,
+                };
+                if !output.entry(out, entry) {
+                    self.aborted.store(true, Ordering::SeqCst);
+                    return;
+                }
+            }
+            if let Some(size) = self.buffer_policy.grow(buf.len(), len) {
+                // keep reading with the current buffer if the larger one can't be allocated
+                if let Ok(larger) = self.buffers.take(size) {
+                    self.buffers.put(mem::replace(buf, larger));
+                }
+            }
+        }
+    }
+    /// Keep the directory open for a subdirectory to be opened relative to it, unless the budget
+    /// has to be left to the directories the workers are about to read
+    fn child_parent(&self, child: Node<'_>, fd: &SharedFd) -> Option<SharedFd> {
+        let open_fds = self.open_fds.load(Ordering::SeqCst);
+        if !is_short(child) || open_fds + self.threads < self.max_open_fds.load(Ordering::SeqCst) {
+            return Some(fd.clone());
+        }
+        bump(&self.stats.spilled_opens);
+        None
+    }
+    fn release<O: Output>(&self, node: Node<'_>, fd: SharedFd, output: &O, out: &mut O::Local) {
+        if let Some(fd) = fd.release() {
+            let result = close(fd);
+            self.open_fds.fetch_sub(1, Ordering::SeqCst);
+            if let Err(err) = result {
+                self.error(
+                    Error::new(Operation::Close, node.to_path_buf(), err),
+                    output,
+                    out,
+                );
+            }
+        }
+    }
+    /// Release the parent directory of `node` once it was opened relative to it
+    fn release_parent<O: Output>(
+        &self,
+        node: Node<'_>,
+        parent: SharedFd,
+        output: &O,
+        out: &mut O::Local,
+    ) {
+        if let Some(fd) = parent.release() {
+            let result = close(fd);
+            self.open_fds.fetch_sub(1, Ordering::SeqCst);
+            if let Err(err) = result {
+                let parent_path = node.parent().unwrap_or(node).to_path_buf();
+                self.error(Error::new(Operation::Close, parent_path, err), output, out);
+            }
+        }
+    }
+    fn error<O: Output>(&self, err: Error, output: &O, out: &mut O::Local) {
+        bump(&self.stats.errors);
+        match self.error_policy {
+            ErrorPolicy::Continue => eprintln!("{err}"),
+            ErrorPolicy::Abort => {
+                if !self.aborted.swap(true, Ordering::SeqCst) {
+                    output.error(out, err);
+                }
+            }
+            ErrorPolicy::Collect => output.error(out, err),
+        }
+    }
+}
+
+/// Marks a job as finished when dropped, so that the walk winds down even if the output panics
+struct Finished<'a>(&'a Shared);
+
+impl Drop for Finished<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.aborted.store(true, Ordering::SeqCst);
+        }
+        if self.0.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.wake.notify_all();
+        }
+    }
+}
+
+/// Whether the kernel can resolve the path of `node` on its own, without opening it relative to
+/// its parent
+fn is_short(node: Node<'_>) -> bool {
+    node.path_len() < libc::PATH_MAX as usize
+}
+
+/// An in-progress walk, yielding every entry below the root
+///
+/// Dropping the `Walk` aborts the walk and waits for the worker threads to close the
+/// directories still open and exit. A walk started on a [`WalkPool`] borrows it, and leaves the
+/// threads to the pool.
+pub struct Walk<'p> {
+    shared: Arc<Shared>,
+    recv: flume::Receiver<Result<DirEntry>>,
+    /// the worker threads of a walk which does not run on a pool
+    threads: Vec<thread::JoinHandle<bool>>,
+    /// set once the error aborting the walk was yielded
+    finished: bool,
+    workers: PhantomData<Workers<'p>>,
+}
+
+impl Walk<'_> {
+    /// Counters collected so far
+    pub fn stats(&self) -> WalkStats {
+        self.shared.stats.snapshot(
+            &self.shared.buffers,
+            &self.shared.throttle,
+            &self.shared.arena,
+        )
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<DirEntry>;
+    fn next(&mut self) -> Option<Result<DirEntry>> {
+        if self.finished {
+            return None;
+        }
+        match self.recv.recv() {
+            Ok(Ok(entry)) => Some(Ok(entry)),
+            Ok(Err(err)) => {
+                self.finished = self.shared.error_policy == ErrorPolicy::Abort;
+                Some(Err(err))
+            }
+            // every worker is done
+            Err(flume::RecvError::Disconnected) => None,
+        }
+    }
+}
+
+impl Drop for Walk<'_> {
+    fn drop(&mut self) {
+        self.shared.aborted.store(true, Ordering::SeqCst);
+        // workers waiting for room in the channel see it disconnected, idle ones wake up
+        drop(mem::replace(&mut self.recv, flume::bounded(0).1));
+        self.shared.wake.notify_all();
+        for thread in self.threads.drain(..) {
+            // a worker which panicked aborted the walk already
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dir_entry::tests::dirent;
+    use std::{fs, os::unix::fs::symlink, process};
+
+    #[test]
+    #[should_panic = "buffer size must be at least"]
+    fn rejects_buffers_smaller_than_a_record() {
+        let _ = Walker::new("/").buffer_size(BufferPolicy::MIN_SIZE - 1);
+    }
+
+    #[test]
+    #[should_panic = "buffer size must be at most"]
+    fn rejects_buffers_larger_than_the_kernel_reads() {
+        let _ = Walker::new("/").buffer_policy(BufferPolicy::Adaptive {
+            initial: 1024,
+            max: isize::MAX as usize,
+        });
+    }
+
+    #[test]
+    fn resolves_unknown_types_with_fstatat() {
+        let root =
+            std::env::temp_dir().join(format!("recursive_dir_walk-unknown-{}", process::id()));
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("file"), b"").unwrap();
+        symlink("file", root.join("link")).unwrap();
+        let dir = fs::File::open(&root).unwrap();
+
+        let mut data = Vec::new();
+        for name in ["dir", "file", "link", "missing"] {
+            data.extend(dirent(1, libc::DT_UNKNOWN, name.as_bytes()));
+        }
+        let buf = Buffer::from_slice(&data);
+        let stats = Counters::default();
+        let resolved: Vec<_> = DirEntryIter::new(&buf)
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                assert!(entry.ty.is_unknown());
+                let result = resolve_type(dir.as_fd(), &mut entry, &stats);
+                (entry.ty, result.map_err(|err| err.kind()))
+            })
+            .collect();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            resolved,
+            [
+                (EntryType::Dir, Ok(())),
+                (EntryType::Regular, Ok(())),
+                (EntryType::Symlink, Ok(())),
+                (EntryType::Unknown, Err(io::ErrorKind::NotFound)),
+            ]
+        );
+        assert_eq!(stats.stat_fallbacks.load(Ordering::Relaxed), 4);
+    }
+}
@@ -0,0 +1,60 @@
+use std::process::{Command, Stdio};
+
+use recursive_dir_walk::BufferPolicy;
+
+mod common;
+
+use common::TempTree;
+
+fn command() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_recursive_dir_walk"))
+}
+
+#[test]
+fn prints_every_path() {
+    let tree = TempTree::build("cli", &[5, 5], 3).unwrap();
+    let output = command().arg(tree.path()).output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let mut expected = common::paths(tree.path());
+    expected.insert(tree.path().to_owned());
+    assert_eq!(common::lines(&output.stdout), expected);
+}
+
+#[test]
+fn exits_quietly_on_a_closed_pipe() {
+    let tree = TempTree::build("cli-pipe", &[5, 5], 3).unwrap();
+    for min_depth in ["0", "1"] {
+        let mut child = command()
+            .args(["--min-depth", min_depth])
+            .arg(tree.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // like `| head -0`
+        drop(child.stdout.take());
+        let output = child.wait_with_output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stderr}");
+        assert!(stderr.is_empty(), "{stderr}");
+    }
+}
+
+#[test]
+fn rejects_buffers_outside_the_limits_of_the_usage() {
+    for size in [BufferPolicy::MIN_SIZE - 1, BufferPolicy::MAX_SIZE + 1] {
+        let output = command()
+            .args(["--buffer-size", &size.to_string(), "/"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let limits = format!(
+            "between {} and {}",
+            BufferPolicy::MIN_SIZE,
+            BufferPolicy::MAX_SIZE
+        );
+        assert!(stderr.contains(&limits), "{stderr}");
+    }
+}
@@ -0,0 +1,96 @@
+//! Trees generated for the integration tests and the benchmarks, which include this module with
+//! `#[path]`
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeSet,
+    env,
+    ffi::OsStr,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+/// A tree of directories and empty files in the temporary directory, removed when dropped
+pub struct TempTree(PathBuf);
+
+impl TempTree {
+    /// Build a tree with `fanout[depth]` subdirectories `dir{i}` in every directory at `depth`,
+    /// and `files` files `file{i}` in every directory
+    pub fn build(name: &str, fanout: &[usize], files: usize) -> io::Result<Self> {
+        fn level(dir: &Path, fanout: &[usize], files: usize) -> io::Result<()> {
+            for i in 0..files {
+                fs::write(dir.join(format!("file{i}")), [])?;
+            }
+            if let Some((&n, rest)) = fanout.split_first() {
+                for i in 0..n {
+                    let sub = dir.join(format!("dir{i}"));
+                    fs::create_dir(&sub)?;
+                    level(&sub, rest, files)?;
+                }
+            }
+            Ok(())
+        }
+        let root = env::temp_dir().join(format!("recursive_dir_walk-{name}-{}", process::id()));
+        fs::create_dir(&root)?;
+        let tree = TempTree(root);
+        level(&tree.0, fanout, files)?;
+        Ok(tree)
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Every path below `dir`, listed with `std::fs`
+pub fn paths(dir: &Path) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            paths.extend(self::paths(&entry.path()));
+        }
+        paths.insert(entry.path());
+    }
+    paths
+}
+
+/// The paths written one per line, as by `Walker::write_paths`
+pub fn lines(output: &[u8]) -> BTreeSet<PathBuf> {
+    assert!(
+        output.is_empty() || output.ends_with(b"\n"),
+        "unterminated line"
+    );
+    output
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| PathBuf::from(OsStr::from_bytes(line)))
+        .collect()
+}
+
+/// Set in the child processes started by [`in_child_process`], to the name of their test
+const CHILD_TEST: &str = "RECURSIVE_DIR_WALK_CHILD_TEST";
+
+/// Run the test `name` alone in a child process and wait for it to pass, for tests changing what
+/// every thread of the process shares, like its credentials or its limits
+///
+/// Returns whether the caller is that child process, and has to run the test.
+pub fn in_child_process(name: &str) -> bool {
+    if env::var_os(CHILD_TEST).is_some_and(|test| test == name) {
+        return true;
+    }
+    let status = Command::new(env::current_exe().unwrap())
+        .args([name, "--exact", "--nocapture"])
+        .env(CHILD_TEST, name)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{name} failed in a child process");
+    false
+}
@@ -0,0 +1,100 @@
+use std::{
+    ffi::CStr,
+    fs, io,
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::PathBuf,
+    process,
+};
+
+use recursive_dir_walk::{ErrorPolicy, Walker};
+
+const DEPTH: usize = 5000;
+const NAME: &CStr = c"deep";
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn open_dir(dirfd: RawFd, name: &CStr) -> io::Result<RawFd> {
+    check(unsafe {
+        libc::openat(
+            dirfd,
+            name.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    })
+}
+
+/// Build a chain of `DEPTH` nested directories below `root`, relative to directory fds because
+/// the full path does not fit into `PATH_MAX`
+fn build(root: &CStr) -> io::Result<()> {
+    let mut fd = open_dir(libc::AT_FDCWD, root)?;
+    for _ in 0..DEPTH {
+        check(unsafe { libc::mkdirat(fd, NAME.as_ptr(), 0o755) })?;
+        let child = open_dir(fd, NAME)?;
+        unsafe { libc::close(fd) };
+        fd = child;
+    }
+    unsafe { libc::close(fd) };
+    Ok(())
+}
+
+/// Remove the chain bottom-up, climbing with `..` so only two fds are open at a time
+fn remove(root: &CStr) -> io::Result<()> {
+    let mut fd = open_dir(libc::AT_FDCWD, root)?;
+    for _ in 0..DEPTH - 1 {
+        let child = open_dir(fd, NAME)?;
+        unsafe { libc::close(fd) };
+        fd = child;
+    }
+    for _ in 0..DEPTH {
+        check(unsafe { libc::unlinkat(fd, NAME.as_ptr(), libc::AT_REMOVEDIR) })?;
+        let parent = open_dir(fd, c"..")?;
+        unsafe { libc::close(fd) };
+        fd = parent;
+    }
+    unsafe { libc::close(fd) };
+    Ok(())
+}
+
+struct DeepTree(PathBuf);
+
+impl Drop for DeepTree {
+    fn drop(&mut self) {
+        let root = std::ffi::CString::new(self.0.as_os_str().as_bytes()).unwrap();
+        remove(&root).ok();
+        fs::remove_dir(&self.0).ok();
+    }
+}
+
+#[test]
+fn deeper_than_path_max() {
+    let root = std::env::temp_dir().join(format!("recursive_dir_walk-deep-{}", process::id()));
+    fs::create_dir(&root).unwrap();
+    let tree = DeepTree(root);
+    let c_root = std::ffi::CString::new(tree.0.as_os_str().as_bytes()).unwrap();
+    build(&c_root).unwrap();
+
+    let mut count = 0;
+    let mut deepest = Vec::new();
+    for entry in Walker::new(&tree.0).error_policy(ErrorPolicy::Abort).walk() {
+        let entry = entry.unwrap();
+        assert!(entry.file_type().is_dir());
+        count += 1;
+        if entry.path().as_slice().len() > deepest.len() {
+            deepest = entry.path().as_slice().to_vec();
+        }
+    }
+    assert_eq!(count, DEPTH);
+
+    let mut expected = tree.0.as_os_str().as_bytes().to_vec();
+    for _ in 0..DEPTH {
+        expected.push(b'/');
+        expected.extend(NAME.to_bytes());
+    }
+    assert!(expected.len() > libc::PATH_MAX as usize);
+    assert_eq!(deepest, expected);
+}
@@ -0,0 +1,114 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use recursive_dir_walk::{ErrorPolicy, Operation, Walker};
+
+/// A root which does not exist, so that the walk fails on its first open
+fn missing_root() -> PathBuf {
+    std::env::temp_dir().join(format!("recursive_dir_walk-missing-{}", process::id()))
+}
+
+/// A regular file, removed when dropped
+struct FileRoot(PathBuf);
+
+impl FileRoot {
+    fn create(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("recursive_dir_walk-{name}-{}", process::id()));
+        fs::write(&path, b"").unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for FileRoot {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn assert_open_error(err: &recursive_dir_walk::Error, path: &Path, errno: i32) {
+    assert_eq!(err.operation(), Operation::Open);
+    assert_eq!(err.path().to_path(), path);
+    assert_eq!(err.raw_os_error(), Some(errno));
+}
+
+#[test]
+fn abort_returns_the_first_error() {
+    let root = missing_root();
+    let entries = AtomicUsize::new(0);
+    let err = Walker::new(&root)
+        .error_policy(ErrorPolicy::Abort)
+        .for_each(|_| {
+            entries.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap_err();
+    assert_open_error(&err, &root, libc::ENOENT);
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(entries.into_inner(), 0);
+}
+
+#[test]
+fn abort_ends_the_walk_after_the_error() {
+    let root = FileRoot::create("abort-file");
+    let mut walk = Walker::new(&root.0).error_policy(ErrorPolicy::Abort).walk();
+    let err = walk.next().unwrap().unwrap_err();
+    assert_open_error(&err, &root.0, libc::ENOTDIR);
+    assert!(walk.next().is_none());
+    assert!(walk.next().is_none());
+}
+
+#[test]
+fn collect_returns_every_error() {
+    let root = FileRoot::create("collect-file");
+    let summary = Walker::new(&root.0)
+        .error_policy(ErrorPolicy::Collect)
+        .for_each(|_| {})
+        .unwrap();
+    assert_eq!(summary.errors.len(), 1);
+    assert_open_error(&summary.errors[0], &root.0, libc::ENOTDIR);
+    assert_eq!(summary.stats.errors, 1);
+
+    let root = missing_root();
+    let errors: Vec<_> = Walker::new(&root)
+        .error_policy(ErrorPolicy::Collect)
+        .walk()
+        .collect();
+    assert_eq!(errors.len(), 1);
+    assert_open_error(errors[0].as_ref().unwrap_err(), &root, libc::ENOENT);
+}
+
+#[test]
+fn continue_counts_errors() {
+    let root = missing_root();
+    let summary = Walker::new(&root)
+        .error_policy(ErrorPolicy::Continue)
+        .for_each(|_| {})
+        .unwrap();
+    assert!(summary.errors.is_empty());
+    assert_eq!(summary.stats.errors, 1);
+}
+
+#[test]
+fn reports_a_root_with_a_zero_character() {
+    let root = Path::new("a\0b");
+    let check = |err: &recursive_dir_walk::Error| {
+        assert_eq!(err.operation(), Operation::Open);
+        assert_eq!(err.path().to_path(), Path::new("a"));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    };
+    let walker = Walker::new(root).error_policy(ErrorPolicy::Abort);
+    check(&walker.for_each(|_| {}).unwrap_err());
+    let errors: Vec<_> = walker.walk().collect();
+    assert_eq!(errors.len(), 1);
+    check(errors[0].as_ref().unwrap_err());
+    let summary = Walker::new(root)
+        .error_policy(ErrorPolicy::Collect)
+        .for_each(|_| {})
+        .unwrap();
+    assert_eq!(summary.errors.len(), 1);
+    check(&summary.errors[0]);
+}
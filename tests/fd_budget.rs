@@ -0,0 +1,56 @@
+use std::{collections::BTreeSet, fs, mem, path::PathBuf};
+
+use recursive_dir_walk::{ErrorPolicy, Walker};
+
+mod common;
+
+use common::TempTree;
+
+fn walk(walker: &Walker) -> (BTreeSet<PathBuf>, recursive_dir_walk::WalkStats) {
+    let mut paths = BTreeSet::new();
+    let mut walk = walker.walk();
+    for entry in walk.by_ref() {
+        paths.insert(entry.unwrap().into_path().into_path_buf());
+    }
+    (paths, walk.stats())
+}
+
+#[test]
+fn walks_within_one_open_directory() {
+    let tree = TempTree::build("fd-budget", &[4, 4, 4], 2).unwrap();
+    let walker = Walker::new(tree.path())
+        .threads(4)
+        .max_open_fds(1)
+        .error_policy(ErrorPolicy::Abort);
+    let (paths, stats) = walk(&walker);
+    assert_eq!(paths, common::paths(tree.path()));
+    assert!(stats.spilled_opens > 0);
+}
+
+#[test]
+fn requeues_opens_failing_with_emfile() {
+    if !common::in_child_process("requeues_opens_failing_with_emfile") {
+        return;
+    }
+    let tree = TempTree::build("emfile", &[10, 10, 4], 2).unwrap();
+    // a budget far above the few fds the process is left with
+    let walker = Walker::new(tree.path())
+        .threads(4)
+        .max_open_fds(10_000)
+        .error_policy(ErrorPolicy::Abort);
+    let mut saved: libc::rlimit64 = unsafe { mem::zeroed() };
+    assert_eq!(
+        unsafe { libc::getrlimit64(libc::RLIMIT_NOFILE, &mut saved) },
+        0
+    );
+    let open = fs::read_dir("/proc/self/fd").unwrap().count();
+    let limit = libc::rlimit64 {
+        rlim_cur: open as u64 + 8,
+        ..saved
+    };
+    assert_eq!(unsafe { libc::setrlimit64(libc::RLIMIT_NOFILE, &limit) }, 0);
+    let (paths, stats) = walk(&walker);
+    unsafe { assert_eq!(libc::setrlimit64(libc::RLIMIT_NOFILE, &saved), 0) };
+    assert_eq!(paths, common::paths(tree.path()));
+    assert!(stats.emfile_requeues > 0);
+}
@@ -0,0 +1,39 @@
+use std::{
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use recursive_dir_walk::{ErrorPolicy, Walker};
+
+mod common;
+
+/// Unprivileged user the test switches to when run as root
+const NOBODY: libc::uid_t = 65534;
+
+#[test]
+fn retries_without_noatime() {
+    if !common::in_child_process("retries_without_noatime") {
+        return;
+    }
+    // `O_NOATIME` is refused on directories owned by someone else, unless the caller is
+    // privileged
+    if unsafe { libc::geteuid() } == 0 {
+        unsafe {
+            assert_eq!(libc::setgroups(0, std::ptr::null()), 0);
+            assert_eq!(libc::setgid(NOBODY), 0);
+            assert_eq!(libc::setuid(NOBODY), 0);
+        }
+    }
+    // only the root is opened, and it belongs to root
+    let walker = Walker::new("/")
+        .max_depth(1)
+        .error_policy(ErrorPolicy::Abort);
+    let entries = AtomicUsize::new(0);
+    let summary = walker
+        .for_each(|_| {
+            entries.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+    assert_eq!(summary.stats.noatime_retries, 1);
+    assert_eq!(entries.into_inner(), fs::read_dir("/").unwrap().count());
+}
@@ -0,0 +1,148 @@
+use std::{
+    fs,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use recursive_dir_walk::{ErrorPolicy, Sink, WalkPool, Walker};
+
+mod common;
+
+use common::TempTree;
+
+fn proc_entries(dir: &str) -> usize {
+    fs::read_dir(dir).unwrap().count()
+}
+
+/// The process' threads, once the ones which were joined have disappeared from `/proc`
+fn settled_threads(expected: usize) -> usize {
+    for _ in 0..100 {
+        if proc_entries("/proc/self/task") <= expected {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    proc_entries("/proc/self/task")
+}
+
+#[test]
+fn walks_without_leaking() {
+    // alone in its process, for the threads and fds of other tests not to be counted
+    if !common::in_child_process("walks_without_leaking") {
+        return;
+    }
+    // three levels of five directories, each with three files
+    let tree = TempTree::build("pool", &[5, 5, 5], 3).unwrap();
+    let paths = common::paths(tree.path());
+    let expected = paths.len();
+    let walker = Walker::new(tree.path()).error_policy(ErrorPolicy::Abort);
+    let fds = proc_entries("/proc/self/fd");
+    let threads = proc_entries("/proc/self/task");
+
+    let pool = WalkPool::new(4);
+    assert_eq!(pool.threads(), 4);
+
+    // one after another, with every kind of output
+    for _ in 0..3 {
+        let entries = AtomicUsize::new(0);
+        pool.for_each(&walker, |_| {
+            entries.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+        assert_eq!(entries.into_inner(), expected);
+    }
+    assert_eq!(pool.walk(&walker).map(Result::unwrap).count(), expected);
+    let written = Mutex::new(Vec::new());
+    let sink = Sink::Callback(Box::new(|chunk: &[u8]| {
+        // chunks hold whole lines
+        assert!(chunk.ends_with(b"\n"));
+        written.lock().unwrap().extend_from_slice(chunk);
+        Ok(())
+    }));
+    pool.write_paths(&walker, sink).unwrap();
+    let written = written.into_inner().unwrap();
+    assert_eq!(written.iter().filter(|&&b| b == b'\n').count(), expected);
+    assert_eq!(common::lines(&written), paths);
+
+    // at the same time
+    thread::scope(|scope| {
+        let walks: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| pool.walk(&walker).map(Result::unwrap).count()))
+            .collect();
+        for walk in walks {
+            assert_eq!(walk.join().unwrap(), expected);
+        }
+    });
+
+    // dropped before the end
+    assert_eq!(pool.walk(&walker).take(5).count(), 5);
+
+    // a panicking callback is reported to the caller and leaves the pool usable
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+        pool.for_each(&walker, |_| panic!("callback panicked"))
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(pool.walk(&walker).count(), expected);
+
+    drop(pool);
+    assert_eq!(proc_entries("/proc/self/fd"), fds);
+    assert_eq!(settled_threads(threads), threads);
+}
+
+/// Run `f` on its own thread, failing if it does not return within a minute
+fn within_a_minute<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    let (send, recv) = std::sync::mpsc::channel();
+    thread::spawn(move || send.send(f()).unwrap());
+    recv.recv_timeout(Duration::from_secs(60))
+        .expect("the walks deadlocked")
+}
+
+#[test]
+fn runs_walks_waiting_on_their_consumer_side_by_side() {
+    // more entries than a walk buffers ahead of its consumer
+    let tree = TempTree::build("pool-side-by-side", &[10, 10, 10], 10).unwrap();
+    let expected = common::paths(tree.path()).len();
+    // every walk wants every thread of the pool
+    let walker = Walker::new(tree.path())
+        .threads(2)
+        .error_policy(ErrorPolicy::Abort);
+    let pool = std::sync::Arc::new(WalkPool::new(2));
+
+    let (pool2, walker2) = (pool.clone(), walker.clone());
+    let zipped = within_a_minute(move || pool2.walk(&walker2).zip(pool2.walk(&walker2)).count());
+    assert_eq!(zipped, expected);
+
+    let entries = within_a_minute(move || {
+        let mut walk = pool.walk(&walker);
+        walk.next().unwrap().unwrap();
+        let entries = AtomicUsize::new(0);
+        pool.for_each(&walker, |_| {
+            entries.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+        assert_eq!(walk.count(), expected - 1);
+        entries.into_inner()
+    });
+    assert_eq!(entries, expected);
+}
+
+#[test]
+fn dropped_walk_joins_its_threads() {
+    if !common::in_child_process("dropped_walk_joins_its_threads") {
+        return;
+    }
+    let tree = TempTree::build("dropped-walk", &[100, 10], 1).unwrap();
+    let walker = Walker::new(tree.path()).threads(8);
+    let fds = proc_entries("/proc/self/fd");
+    let threads = proc_entries("/proc/self/task");
+
+    assert_eq!(walker.walk().take(5).count(), 5);
+    // a joined thread may linger in `/proc` for a moment, a detached one for much longer
+    assert_eq!(proc_entries("/proc/self/fd"), fds);
+    assert_eq!(settled_threads(threads), threads);
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::CString,
+    fs,
+    os::unix::{ffi::OsStrExt, fs::symlink, net::UnixListener},
+    path::Path,
+    process,
+    sync::Mutex,
+};
+
+use recursive_dir_walk::{dir_entry::EntryType, ErrorPolicy, Sink, Walker};
+
+mod common;
+
+use common::TempTree;
+
+#[test]
+fn frees_the_paths_of_finished_subtrees() {
+    // 2420 directories, whose nodes take more than 100 KiB together
+    let tree = TempTree::build("arena", &[20, 20, 5], 0).unwrap();
+    let summary = Walker::new(tree.path())
+        .threads(1)
+        .queue_memory_limit(0)
+        .error_policy(ErrorPolicy::Abort)
+        .for_each(|_| {})
+        .unwrap();
+    // depth-first, only the directories along the current path and their siblings are kept
+    assert!(
+        summary.stats.peak_path_arena_bytes < 32 << 10,
+        "{:?}",
+        summary.stats
+    );
+    assert!(
+        summary.stats.peak_queued_bytes <= 32 << 10,
+        "{:?}",
+        summary.stats
+    );
+}
+
+#[test]
+fn writes_paths_to_a_file() {
+    let tree = TempTree::build("write-file", &[5, 5], 3).unwrap();
+    let out = std::env::temp_dir().join(format!("recursive_dir_walk-paths-{}", process::id()));
+    let file = fs::File::create(&out).unwrap();
+    Walker::new(tree.path())
+        .threads(4)
+        .error_policy(ErrorPolicy::Abort)
+        .write_paths(Sink::File(file))
+        .unwrap();
+    let written = fs::read(&out).unwrap();
+    fs::remove_file(&out).unwrap();
+    let expected = common::paths(tree.path());
+    assert_eq!(
+        written.iter().filter(|&&b| b == b'\n').count(),
+        expected.len()
+    );
+    assert_eq!(common::lines(&written), expected);
+}
+
+#[test]
+fn reports_special_files_without_following_symlinks() {
+    // `dir0` holds `file0`, which must be found once, through `dir0` alone
+    let tree = TempTree::build("special", &[1], 1).unwrap();
+    let root = tree.path();
+    symlink("dir0", root.join("dir-link")).unwrap();
+    symlink("missing", root.join("dangling-link")).unwrap();
+    let fifo = CString::new(root.join("fifo").as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+    let _socket = UnixListener::bind(root.join("socket")).unwrap();
+
+    let entries: BTreeMap<_, _> = Walker::new(root)
+        .error_policy(ErrorPolicy::Abort)
+        .walk()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let name = entry
+                .path()
+                .to_path()
+                .strip_prefix(root)
+                .unwrap()
+                .to_owned();
+            (
+                name.into_os_string().into_string().unwrap(),
+                entry.file_type(),
+            )
+        })
+        .collect();
+    let expected = BTreeMap::from([
+        ("dangling-link".to_owned(), EntryType::Symlink),
+        ("dir-link".to_owned(), EntryType::Symlink),
+        ("dir0".to_owned(), EntryType::Dir),
+        ("dir0/file0".to_owned(), EntryType::Regular),
+        ("fifo".to_owned(), EntryType::Fifo),
+        ("file0".to_owned(), EntryType::Regular),
+        ("socket".to_owned(), EntryType::Socket),
+    ]);
+    assert_eq!(entries, expected);
+}
+
+#[test]
+fn depth_limits() {
+    // directories and files at every depth, four levels of entries below the root
+    let tree = TempTree::build("depth", &[2, 2, 2], 1).unwrap();
+    let all = common::paths(tree.path());
+    let depth = |path: &Path| path.strip_prefix(tree.path()).unwrap().components().count();
+
+    for (min_depth, max_depth) in [(0, 0), (0, 1), (1, 1), (2, 3), (3, 10), (4, 4), (3, 2)] {
+        let walked = Mutex::new(BTreeSet::new());
+        let summary = Walker::new(tree.path())
+            .error_policy(ErrorPolicy::Abort)
+            .min_depth(min_depth)
+            .max_depth(max_depth)
+            .for_each(|entry| {
+                let path = entry.to_dir_entry().into_path().into_path_buf();
+                walked.lock().unwrap().insert(path);
+            })
+            .unwrap();
+        // files and directories above `min_depth` are hidden alike
+        let expected: BTreeSet<_> = all
+            .iter()
+            .filter(|path| (min_depth.max(1)..=max_depth).contains(&depth(path)))
+            .cloned()
+            .collect();
+        let case = format!("min {min_depth}, max {max_depth}");
+        assert_eq!(walked.into_inner().unwrap(), expected, "{case}");
+
+        // the directories at `max_depth` are listed but not read
+        let read = match max_depth {
+            0 => 0,
+            _ => {
+                1 + all
+                    .iter()
+                    .filter(|path| path.is_dir() && depth(path) < max_depth)
+                    .count()
+            }
+        };
+        assert_eq!(summary.stats.dirs_read, read as u64, "{case}");
+    }
+}